@@ -0,0 +1,372 @@
+// Several distinct candidate routes between two points, using Yen's k-shortest paths.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+};
+
+use crate::{
+    a_star_observed, best_first_search, check_bounds,
+    cost_model::CostModel,
+    datatypes::HeightMap,
+    expand_cell, line_cells,
+    progress::{NoObserver, SearchObserver},
+    route_cells, Connectivity, Route, RoutingError, SearchStats,
+};
+
+/// How many alternatives to look for, and how different they must be.
+#[derive(Debug, Clone, Copy)]
+pub struct Alternatives {
+    /// The most routes to return, including the cheapest one.
+    pub count: usize,
+    /// The largest fraction of a route's cells that may also lie on a cheaper route which
+    /// was already accepted. 1.0 accepts any route that isn't an exact duplicate.
+    pub max_shared_fraction: f64,
+    /// How many of the k-shortest paths to look at before giving up. Near-duplicates of
+    /// the cheapest route can be very numerous, especially on flat ground.
+    pub max_candidates: usize,
+}
+
+/// Finds up to `alternatives.count` routes from `start` to `end`, cheapest first, each of
+/// which shares at most `alternatives.max_shared_fraction` of its cells with the cheaper
+/// routes before it.
+///
+/// This walks the k-shortest loopless paths in order of cost (Yen's algorithm), and keeps
+/// the ones that are different enough. It fails like `a_star` if not even one route can be
+/// found, but returning fewer than `count` routes is not an error.
+pub fn alternative_routes(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    alternatives: Alternatives,
+) -> Result<Vec<Route>, RoutingError> {
+    alternative_routes_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        alternatives,
+        &mut NoObserver,
+    )
+}
+
+/// Like `alternative_routes`, but reports the progress of all the searches together to
+/// `observer`, which may cancel them.
+pub fn alternative_routes_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    alternatives: Alternatives,
+    observer: &mut impl SearchObserver,
+) -> Result<Vec<Route>, RoutingError> {
+    check_bounds(height_map, &[start, end])?;
+    let (first, mut stats) = a_star_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        |from, to, height_map| cost_model.lower_bound(height_map, from, to, connectivity),
+        observer,
+    )?;
+
+    // Every k-shortest path so far, whether or not it was accepted, with the cost of
+    // reaching each of its points.
+    let mut shortest = Vec::new();
+    let mut seen = HashSet::from([first.path.clone()]);
+    let mut candidates = BinaryHeap::from([Reverse((first.cost, first.path))]);
+    let mut accepted: Vec<Route> = Vec::new();
+    let mut accepted_cells = Vec::new();
+
+    while accepted.len() < alternatives.count && shortest.len() < alternatives.max_candidates {
+        let Some(Reverse((cost, path))) = candidates.pop() else {
+            break;
+        };
+
+        let cells = route_cells(&path);
+        let is_distinct = accepted_cells.iter().all(|other: &HashSet<_>| {
+            let shared = cells.iter().filter(|&cell| other.contains(cell)).count();
+            shared as f64 <= alternatives.max_shared_fraction * cells.len() as f64
+        });
+        if is_distinct {
+            accepted.push(Route {
+                path: path.clone(),
+                cost,
+            });
+            accepted_cells.push(cells.into_iter().collect());
+        }
+
+        let prefix_costs = prefix_costs(&path, height_map, cost_model)?;
+        shortest.push((path, prefix_costs));
+        let (path, prefix_costs) = shortest.last().unwrap();
+
+        // Branch off the newest path at every point before the end.
+        for spur_index in 0..path.len() - 1 {
+            let root = &path[..=spur_index];
+
+            // Don't repeat the first step after `root` of any path that shares it,
+            // and don't revisit `root` itself, so the new path is both new and loopless.
+            let spur = Spur {
+                from: root[spur_index],
+                steps: shortest
+                    .iter()
+                    .filter(|(other, _)| other.len() > spur_index + 1 && other.starts_with(root))
+                    .map(|(other, _)| (other[spur_index], other[spur_index + 1]))
+                    .collect(),
+                cells: root[..spur_index]
+                    .iter()
+                    .copied()
+                    .chain(line_cells_between(root))
+                    .collect(),
+            };
+
+            let Some((spur_path, spur_cost)) = spur_route(
+                &spur,
+                end,
+                height_map,
+                cost_model,
+                connectivity,
+                &mut stats,
+                observer,
+            )?
+            else {
+                continue;
+            };
+
+            let candidate = root
+                .iter()
+                .copied()
+                .chain(spur_path.into_iter().skip(1))
+                .collect::<Vec<_>>();
+            let cost = prefix_costs[spur_index]
+                .checked_add(spur_cost)
+                .ok_or(RoutingError::CostOverflow)?;
+            if seen.insert(candidate.clone()) {
+                candidates.push(Reverse((cost, candidate)));
+            }
+        }
+    }
+
+    Ok(accepted)
+}
+
+/// The cost of reaching each point of `path` from its start.
+fn prefix_costs(
+    path: &[(usize, usize)],
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+) -> Result<Vec<usize>, RoutingError> {
+    let mut costs: Vec<usize> = vec![0];
+    for step in path.windows(2) {
+        // Every step was already taken by a search, so the cost model allows it.
+        let step_cost = cost_model.step_cost(height_map, step[0], step[1]).unwrap();
+        let cost = costs
+            .last()
+            .unwrap()
+            .checked_add(step_cost)
+            .ok_or(RoutingError::CostOverflow)?;
+        costs.push(cost);
+    }
+    Ok(costs)
+}
+
+/// The cells strictly inside the segments of `path`, which only exist for any-angle paths.
+fn line_cells_between(path: &[(usize, usize)]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    path.windows(2).flat_map(|segment| {
+        let cells = line_cells(segment[0], segment[1]);
+        let inner = cells.len().saturating_sub(1);
+        cells.into_iter().take(inner).skip(1)
+    })
+}
+
+/// A step between two cells, from the first to the second.
+type Step = ((usize, usize), (usize, usize));
+
+/// A path and its cost.
+type CostedPath = (Vec<(usize, usize)>, usize);
+
+/// Where a new path branches off an earlier one, and what it may not use.
+struct Spur {
+    from: (usize, usize),
+    /// The steps that earlier paths with the same root take from `from`.
+    steps: HashSet<Step>,
+    /// Cells of the shared root before `from`.
+    cells: HashSet<(usize, usize)>,
+}
+
+/// A* search from `spur.from` to `end` that never takes a step in `spur.steps`,
+/// nor enters or crosses a cell in `spur.cells`. The expanded cells are added to `stats`.
+fn spur_route(
+    spur: &Spur,
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    stats: &mut SearchStats,
+    observer: &mut impl SearchObserver,
+) -> Result<Option<CostedPath>, RoutingError> {
+    best_first_search(
+        spur.from,
+        |position| position == end,
+        |position| cost_model.lower_bound(height_map, position, end, connectivity),
+        |curr, relaxations| {
+            expand_cell(curr, relaxations, height_map, cost_model, connectivity);
+            relaxations.retain(|relaxation| {
+                let (from, to) = (relaxation.from, relaxation.position);
+                !spur.steps.contains(&(from, to))
+                    && line_cells(from, to)
+                        .into_iter()
+                        .skip(1)
+                        .all(|cell| !spur.cells.contains(&cell))
+            });
+        },
+        connectivity.can_reopen(),
+        stats,
+        observer,
+    )
+}
+
+/// The voxels of each route, one group per route so that `magica_voxel::write_to_vox`
+/// gives every alternative its own colour. Each route sits one voxel above the terrain.
+pub fn route_voxels(routes: &[Route], height_map: &HeightMap) -> Vec<Vec<(usize, usize, usize)>> {
+    routes
+        .iter()
+        .map(|route| {
+            route_cells(&route.path)
+                .into_iter()
+                .map(|(x, y)| (x, y, height_map[(x, y)] as usize + 1))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cost_model::{AbsoluteClimb, FlatDistance, NoGo},
+        datatypes::Raster,
+        dijkstra,
+    };
+
+    fn shared_fraction(route: &Route, other: &Route) -> f64 {
+        let other = route_cells(&other.path).into_iter().collect::<HashSet<_>>();
+        let cells = route_cells(&route.path);
+        let shared = cells.iter().filter(|&cell| other.contains(cell)).count();
+        shared as f64 / cells.len() as f64
+    }
+
+    #[test]
+    fn k_shortest_routes_are_ranked_and_loopless() {
+        let height_map = HeightMap::from_fn(12, 12, |(x, y)| (x as i32 * 7 + y as i32 * 13) % 11);
+        let cost_model = AbsoluteClimb::default();
+        let alternatives = Alternatives {
+            count: 10,
+            max_shared_fraction: 1.0,
+            max_candidates: 10,
+        };
+
+        let routes = alternative_routes(
+            (0, 0),
+            (11, 8),
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            alternatives,
+        )
+        .unwrap();
+
+        assert_eq!(routes.len(), 10);
+        assert_eq!(
+            routes[0].cost,
+            dijkstra(
+                (0, 0),
+                (11, 8),
+                &height_map,
+                &cost_model,
+                Connectivity::Eight
+            )
+            .unwrap()
+            .cost
+        );
+        for pair in routes.windows(2) {
+            assert!(pair[0].cost <= pair[1].cost);
+            assert_ne!(pair[0].path, pair[1].path);
+        }
+        for route in &routes {
+            let prefix_costs = prefix_costs(&route.path, &height_map, &cost_model).unwrap();
+            assert_eq!(*prefix_costs.last().unwrap(), route.cost);
+            let unique = route.path.iter().collect::<HashSet<_>>();
+            assert_eq!(unique.len(), route.path.len(), "{:?} loops", route.path);
+        }
+    }
+
+    #[test]
+    fn alternatives_go_around_either_side() {
+        // The only open cells form a ring, so there are two ways around it, plus
+        // near-duplicates that cut its corners diagonally.
+        let height_map = HeightMap::new(7, 11, vec![0; 7 * 11]);
+        let mask = Raster::from_fn(7, 11, |(x, y)| !(x == 0 || x == 6 || y == 0 || y == 10));
+        let cost_model = NoGo {
+            inner: FlatDistance,
+            mask,
+        };
+        let alternatives = Alternatives {
+            count: 3,
+            max_shared_fraction: 0.5,
+            max_candidates: 1000,
+        };
+
+        for connectivity in [
+            Connectivity::Four,
+            Connectivity::Eight,
+            Connectivity::AnyAngle,
+        ] {
+            let routes = alternative_routes(
+                (0, 5),
+                (6, 5),
+                &height_map,
+                &cost_model,
+                connectivity,
+                alternatives,
+            )
+            .unwrap();
+
+            assert_eq!(routes.len(), 2, "{:?}", routes);
+            let sides = routes
+                .iter()
+                .map(|route| route_cells(&route.path).contains(&(3, 0)))
+                .collect::<Vec<_>>();
+            assert_ne!(sides[0], sides[1]);
+            assert!(shared_fraction(&routes[1], &routes[0]) <= 0.5);
+        }
+    }
+
+    #[test]
+    fn one_voxel_group_per_route() {
+        let height_map = HeightMap::new(5, 5, vec![3; 5 * 5]);
+        let routes = alternative_routes(
+            (0, 0),
+            (4, 4),
+            &height_map,
+            &FlatDistance,
+            Connectivity::Four,
+            Alternatives {
+                count: 2,
+                max_shared_fraction: 0.5,
+                max_candidates: 100,
+            },
+        )
+        .unwrap();
+
+        let voxels = route_voxels(&routes, &height_map);
+        assert_eq!(voxels.len(), routes.len());
+        assert_eq!(voxels[0].len(), 9);
+        assert!(voxels.iter().flatten().all(|&(_, _, z)| z == 4));
+    }
+}
@@ -0,0 +1,262 @@
+// Routing many independent pairs at once, spread over a thread pool.
+
+use std::{
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    a_star_observed,
+    cost_model::CostModel,
+    datatypes::HeightMap,
+    progress::{NoObserver, Progress, SearchObserver},
+    Connectivity, Route, RoutingError,
+};
+
+/// A `(start, end)` pair of cells to route between.
+pub type Pair = ((usize, usize), (usize, usize));
+
+/// Routes every `(start, end)` pair in `pairs` with A*, in parallel on the current rayon
+/// thread pool (the global one, unless called inside `ThreadPool::install`).
+///
+/// The results are in the same order as `pairs`, and a pair that can't be routed only
+/// fails its own entry.
+pub fn route_batch(
+    pairs: &[Pair],
+    height_map: &HeightMap,
+    cost_model: &(impl CostModel + Sync),
+    connectivity: Connectivity,
+) -> Vec<Result<Route, RoutingError>> {
+    route_batch_observed(pairs, height_map, cost_model, connectivity, &mut NoObserver)
+}
+
+/// Like `route_batch`, but reports the progress of all the searches together to
+/// `observer`, which may cancel them. Once cancelled, every pair that isn't routed yet
+/// fails with `RoutingError::Cancelled`.
+///
+/// `observer` is only called from one thread at a time. `Progress::expanded` is a running
+/// count over the whole batch, and the rest of each `Progress` comes from whichever search
+/// made the report.
+pub fn route_batch_observed(
+    pairs: &[Pair],
+    height_map: &HeightMap,
+    cost_model: &(impl CostModel + Sync),
+    connectivity: Connectivity,
+    observer: &mut (impl SearchObserver + Send),
+) -> Vec<Result<Route, RoutingError>> {
+    let interval = observer.interval().max(1);
+    let shared = Shared {
+        observer: Mutex::new(observer),
+        expanded: AtomicUsize::new(0),
+        cancelled: AtomicBool::new(false),
+    };
+    pairs
+        .par_iter()
+        .map(|&(start, end)| {
+            a_star_observed(
+                start,
+                end,
+                height_map,
+                cost_model,
+                connectivity,
+                |from, to, height_map| cost_model.lower_bound(height_map, from, to, connectivity),
+                &mut SharedObserver {
+                    shared: &shared,
+                    interval,
+                },
+            )
+            .map(|(route, _)| route)
+        })
+        .collect()
+}
+
+/// What the searches of `route_batch_observed` share.
+struct Shared<O> {
+    observer: Mutex<O>,
+    /// Positions expanded by every search so far.
+    expanded: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+/// Hears about every expansion of one search in a batch, and passes every `interval`-th
+/// expansion of the whole batch on to the shared observer.
+struct SharedObserver<'a, O> {
+    shared: &'a Shared<O>,
+    interval: usize,
+}
+
+impl<O: SearchObserver> SearchObserver for SharedObserver<'_, &mut O> {
+    fn progress(&mut self, progress: &Progress) -> ControlFlow<()> {
+        let expanded = self.shared.expanded.fetch_add(1, Ordering::Relaxed) + 1;
+        if expanded.is_multiple_of(self.interval) && !self.shared.cancelled.load(Ordering::Relaxed)
+        {
+            let mut observer = self.shared.observer.lock().unwrap();
+            let progress = Progress {
+                expanded,
+                ..*progress
+            };
+            if observer.progress(&progress).is_break() {
+                self.shared.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+        if self.shared.cancelled.load(Ordering::Relaxed) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    fn interval(&self) -> usize {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{a_star, cost_model::AbsoluteClimb, dijkstra};
+
+    /// Keeps the expansion count of every report, and cancels once `limit` positions are
+    /// expanded.
+    struct Counter {
+        reports: Vec<usize>,
+        limit: usize,
+    }
+
+    impl SearchObserver for Counter {
+        fn progress(&mut self, progress: &Progress) -> ControlFlow<()> {
+            self.reports.push(progress.expanded);
+            if progress.expanded >= self.limit {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+
+        fn interval(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn results_in_input_order_with_errors() {
+        let height_map = HeightMap::from_fn(50, 50, |(x, y)| (x as i32 * 7 + y as i32 * 13) % 11);
+        let cost_model = AbsoluteClimb::default();
+        let pairs = [
+            ((0, 0), (49, 49)),
+            ((10, 40), (45, 3)),
+            ((0, 0), (50, 0)),
+            ((25, 25), (25, 25)),
+            ((49, 0), (0, 49)),
+        ];
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let results =
+            pool.install(|| route_batch(&pairs, &height_map, &cost_model, Connectivity::Eight));
+
+        assert_eq!(results.len(), pairs.len());
+        for (&(start, end), result) in pairs.iter().zip(&results) {
+            let expected = dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight);
+            assert_eq!(
+                result.as_ref().map(|route| route.cost),
+                expected.as_ref().map(|route| route.cost)
+            );
+            if let Ok(route) = result {
+                assert_eq!(route.path.first(), Some(&start));
+                assert_eq!(route.path.last(), Some(&end));
+            }
+        }
+        assert_eq!(
+            results[2],
+            Err(RoutingError::OutOfBounds {
+                point: (50, 0),
+                size: (50, 50)
+            })
+        );
+    }
+
+    #[test]
+    fn one_observer_for_the_whole_batch() {
+        let height_map = HeightMap::from_fn(50, 50, |(x, y)| (x as i32 * 7 + y as i32 * 13) % 11);
+        let cost_model = AbsoluteClimb::default();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let pairs = (0..12)
+            .map(|i| ((i, 0), (49 - i * 3, 49)))
+            .collect::<Vec<_>>();
+
+        let mut counter = Counter {
+            reports: Vec::new(),
+            limit: usize::MAX,
+        };
+        let results = pool.install(|| {
+            route_batch_observed(
+                &pairs,
+                &height_map,
+                &cost_model,
+                Connectivity::Eight,
+                &mut counter,
+            )
+        });
+        assert_eq!(
+            results,
+            route_batch(&pairs, &height_map, &cost_model, Connectivity::Eight)
+        );
+        // Every search reports each expansion but the one that reaches its end.
+        let total = pairs
+            .iter()
+            .map(|&(start, end)| {
+                let (_, stats) = a_star(
+                    start,
+                    end,
+                    &height_map,
+                    &cost_model,
+                    Connectivity::Eight,
+                    |from, to, height_map| {
+                        cost_model.lower_bound(height_map, from, to, Connectivity::Eight)
+                    },
+                )
+                .unwrap();
+                stats.expanded - 1
+            })
+            .sum::<usize>();
+        counter.reports.sort();
+        assert_eq!(counter.reports, (1..=total).collect::<Vec<_>>());
+
+        // Cancelling stops every search that's still going.
+        let mut counter = Counter {
+            reports: Vec::new(),
+            limit: 200,
+        };
+        let results = pool.install(|| {
+            route_batch_observed(
+                &pairs,
+                &height_map,
+                &cost_model,
+                Connectivity::Eight,
+                &mut counter,
+            )
+        });
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(RoutingError::Cancelled { .. }))));
+        for (&(start, end), result) in pairs.iter().zip(&results) {
+            if let Ok(route) = result {
+                let expected = dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight);
+                assert_eq!(Ok(route.cost), expected.map(|route| route.cost));
+            } else {
+                assert!(matches!(result, Err(RoutingError::Cancelled { .. })));
+            }
+        }
+    }
+}
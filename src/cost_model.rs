@@ -0,0 +1,497 @@
+// Edge costs used by the routers.
+
+use crate::{
+    datatypes::{HeightMap, Raster},
+    line_cells, route_cells, Connectivity,
+};
+
+/// Costs are fixed point so that diagonal and any-angle steps can be charged their true
+/// Euclidean length: one cell of horizontal distance (or one unit of climb) costs this much.
+pub const COST_PER_CELL: usize = 1000;
+
+/// Computes what it costs to lay track in a straight line from one cell to another.
+///
+/// The cells are usually neighbours, but any-angle routing asks for the cost of
+/// arbitrarily long straight segments.
+///
+/// Any extra rasters a model needs (land value, soil type, ...) are owned by the model
+/// itself, the routers only ever hand over the height map.
+pub trait CostModel {
+    /// The cost of moving from `from` to `to`, or `None` if the step is not allowed.
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize>;
+
+    /// A lower bound on the cost of any path from `from` to `to` that only takes steps
+    /// allowed by `connectivity`.
+    ///
+    /// This is used as the A* heuristic, so it must never overestimate.
+    /// Returning 0 is always correct, it just makes A* degrade to Dijkstra.
+    fn lower_bound(
+        &self,
+        _height_map: &HeightMap,
+        _from: (usize, usize),
+        _to: (usize, usize),
+        _connectivity: Connectivity,
+    ) -> usize {
+        0
+    }
+
+    /// Whether track may run through `cell` at all, whatever height it runs at.
+    ///
+    /// Routers that leave the terrain surface (see `structures::route_with_structures`)
+    /// check this for the cells their structures run through, where `step_cost` doesn't apply.
+    fn allows_cell(&self, _cell: (usize, usize)) -> bool {
+        true
+    }
+}
+
+impl<M: CostModel + ?Sized> CostModel for &M {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        (**self).step_cost(height_map, from, to)
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        (**self).lower_bound(height_map, from, to, connectivity)
+    }
+
+    fn allows_cell(&self, cell: (usize, usize)) -> bool {
+        (**self).allows_cell(cell)
+    }
+}
+
+/// Euclidean distance between two cells, in cells.
+pub fn distance(from: (usize, usize), to: (usize, usize)) -> f64 {
+    (from.0.abs_diff(to.0) as f64).hypot(from.1.abs_diff(to.1) as f64)
+}
+
+/// The length of the shortest path between two cells that only takes steps allowed by
+/// `connectivity`, in cells: the Manhattan distance for `Four`, the octile distance for
+/// `Eight`, and the Euclidean distance for `AnyAngle`.
+pub fn path_length(from: (usize, usize), to: (usize, usize), connectivity: Connectivity) -> f64 {
+    let (dx, dy) = (from.0.abs_diff(to.0) as f64, from.1.abs_diff(to.1) as f64);
+    match connectivity {
+        Connectivity::Four => dx + dy,
+        Connectivity::Eight => dx.max(dy) + (2f64.sqrt() - 1.0) * dx.min(dy),
+        Connectivity::AnyAngle => dx.hypot(dy),
+    }
+}
+
+/// Converts a step cost measured in cells to fixed point.
+///
+/// This rounds up, while `to_lower_bound` rounds down, so that summing rounded step
+/// costs never undercuts a rounded lower bound and A* stays admissible.
+pub fn to_cost(cells: f64) -> usize {
+    (cells * COST_PER_CELL as f64).ceil() as usize
+}
+
+/// Converts a lower bound measured in cells to fixed point.
+fn to_lower_bound(cells: f64) -> usize {
+    (cells * COST_PER_CELL as f64).floor() as usize
+}
+
+/// The signed height change of a step.
+fn climb(height_map: &HeightMap, from: (usize, usize), to: (usize, usize)) -> f64 {
+    (height_map[to] - height_map[from]) as f64
+}
+
+/// Only the horizontal distance matters, the terrain is ignored.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlatDistance;
+
+impl CostModel for FlatDistance {
+    fn step_cost(
+        &self,
+        _height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        Some(to_cost(distance(from, to)))
+    }
+
+    fn lower_bound(
+        &self,
+        _height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        to_lower_bound(path_length(from, to, connectivity))
+    }
+}
+
+/// Distance plus `weight` for every unit of height gained or lost.
+///
+/// The default weight of 1 gives the original `1 + |Δheight|` step cost.
+#[derive(Debug, Clone, Copy)]
+pub struct AbsoluteClimb {
+    pub weight: f64,
+}
+
+impl Default for AbsoluteClimb {
+    fn default() -> Self {
+        Self { weight: 1.0 }
+    }
+}
+
+impl CostModel for AbsoluteClimb {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        Some(to_cost(
+            distance(from, to) + self.weight * climb(height_map, from, to).abs(),
+        ))
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        // The climbs along any path add up to at least the net height difference.
+        to_lower_bound(
+            path_length(from, to, connectivity) + self.weight * climb(height_map, from, to).abs(),
+        )
+    }
+}
+
+/// Distance plus `weight` times the squared height change, so that one steep step
+/// costs more than several gentle ones.
+#[derive(Debug, Clone, Copy)]
+pub struct SquaredGrade {
+    pub weight: f64,
+}
+
+impl Default for SquaredGrade {
+    fn default() -> Self {
+        Self { weight: 1.0 }
+    }
+}
+
+impl CostModel for SquaredGrade {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        let distance = distance(from, to);
+        let climb = climb(height_map, from, to);
+        // Grade is climb / distance, integrated over the step's length.
+        Some(to_cost(
+            distance + self.weight * climb * climb / distance.max(1.0),
+        ))
+    }
+
+    fn lower_bound(
+        &self,
+        _height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        // The climb could be spread arbitrarily thin, so only the distance is certain.
+        to_lower_bound(path_length(from, to, connectivity))
+    }
+}
+
+/// Distance plus `weight` for every unit of height gained. Going downhill is free.
+#[derive(Debug, Clone, Copy)]
+pub struct UphillPenalty {
+    pub weight: f64,
+}
+
+impl Default for UphillPenalty {
+    fn default() -> Self {
+        Self { weight: 1.0 }
+    }
+}
+
+impl CostModel for UphillPenalty {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        Some(to_cost(
+            distance(from, to) + self.weight * climb(height_map, from, to).max(0.0),
+        ))
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        to_lower_bound(
+            path_length(from, to, connectivity)
+                + self.weight * climb(height_map, from, to).max(0.0),
+        )
+    }
+}
+
+/// Only the height gained or lost, without any distance. On its own this makes every
+/// flat detour free, so it is mostly useful as one objective of `pareto::pareto_routes`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeightChange;
+
+impl CostModel for HeightChange {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        Some(to_cost(climb(height_map, from, to).abs()))
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        _connectivity: Connectivity,
+    ) -> usize {
+        to_lower_bound(climb(height_map, from, to).abs())
+    }
+}
+
+/// Adds a per-cell cost from an extra raster (land value, soil, ...) on top of another model.
+///
+/// The raster is indexed like the height map, and its value is charged as is
+/// (not scaled by `COST_PER_CELL`) when a step enters the cell.
+#[derive(Debug, Clone)]
+pub struct WithRaster<M> {
+    pub inner: M,
+    pub raster: Raster<usize>,
+}
+
+impl<M: CostModel> CostModel for WithRaster<M> {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        Some(
+            self.inner
+                .step_cost(height_map, from, to)?
+                .saturating_add(self.raster[to]),
+        )
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        self.inner.lower_bound(height_map, from, to, connectivity)
+    }
+
+    fn allows_cell(&self, cell: (usize, usize)) -> bool {
+        self.inner.allows_cell(cell)
+    }
+}
+
+/// What `MaxGrade` does with a step that is steeper than the limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SteepSteps {
+    /// The step is not allowed at all.
+    Forbid,
+    /// The step is built as a tunnel (when climbing) or a bridge (when descending),
+    /// which keeps the track itself within the grade limit.
+    /// `cost` is charged per cell of structure, in units of `COST_PER_CELL`,
+    /// instead of the inner model's cost.
+    ///
+    /// This is only a cost approximation, see `structures::route_with_structures` for
+    /// routing that actually tracks where tunnels and bridges go.
+    Structure { cost: f64 },
+}
+
+/// Limits how steep the track may be on top of another model.
+///
+/// `max_grade` is the largest allowed rise over run, in real units under the height map's
+/// `WorldScale` (so height units per cell at the default scale).
+#[derive(Debug, Clone)]
+pub struct MaxGrade<M> {
+    pub inner: M,
+    pub max_grade: f64,
+    pub steep_steps: SteepSteps,
+}
+
+impl<M> MaxGrade<M> {
+    /// Whether the step from `from` to `to` is steeper than `max_grade`.
+    pub fn is_too_steep(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> bool {
+        height_map.grade(from, to).abs() > self.max_grade
+    }
+}
+
+impl<M: CostModel> CostModel for MaxGrade<M> {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        if !self.is_too_steep(height_map, from, to) {
+            return self.inner.step_cost(height_map, from, to);
+        }
+        match self.steep_steps {
+            SteepSteps::Forbid => None,
+            SteepSteps::Structure { cost } => Some(to_cost(cost * distance(from, to))),
+        }
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        match self.steep_steps {
+            // Forbidding steps only ever makes paths more expensive.
+            SteepSteps::Forbid => self.inner.lower_bound(height_map, from, to, connectivity),
+            // A structure may skip whatever the inner model would have charged.
+            SteepSteps::Structure { .. } => 0,
+        }
+    }
+
+    fn allows_cell(&self, cell: (usize, usize)) -> bool {
+        self.inner.allows_cell(cell)
+    }
+}
+
+/// Forbids entering any cell marked in a no-go mask (lakes, protected land, ...) on top of
+/// another model.
+///
+/// The mask is indexed like the height map.
+#[derive(Debug, Clone)]
+pub struct NoGo<M> {
+    pub inner: M,
+    pub mask: Raster<bool>,
+}
+
+impl<M: CostModel> CostModel for NoGo<M> {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        if self.mask[to] {
+            return None;
+        }
+        self.inner.step_cost(height_map, from, to)
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        self.inner.lower_bound(height_map, from, to, connectivity)
+    }
+
+    fn allows_cell(&self, cell: (usize, usize)) -> bool {
+        !self.mask[cell] && self.inner.allows_cell(cell)
+    }
+}
+
+/// Lets a route reuse track that earlier routes already built, on top of another model.
+///
+/// Steps along existing track cost `shared_cost_fraction` of what the inner model charges,
+/// and steps that leave it pay the full cost plus `junction_penalty`, in units of
+/// `COST_PER_CELL`. Joining existing track is free, since that junction is paid for
+/// when the route leaves it again (or it ends at a station on the track).
+///
+/// The existing track is a raster indexed like the height map, so two parallel tracks in
+/// neighbouring cells look like one wide track.
+#[derive(Debug, Clone)]
+pub struct SharedTrack<M> {
+    pub inner: M,
+    pub existing: Raster<bool>,
+    pub shared_cost_fraction: f64,
+    pub junction_penalty: f64,
+}
+
+impl<M> SharedTrack<M> {
+    /// Marks every cell that `path` runs through as existing track.
+    pub fn add_path(&mut self, path: &[(usize, usize)]) {
+        for cell in route_cells(path) {
+            self.existing[cell] = true;
+        }
+    }
+
+    fn is_shared(&self, from: (usize, usize), to: (usize, usize)) -> bool {
+        line_cells(from, to)
+            .into_iter()
+            .all(|cell| self.existing[cell])
+    }
+}
+
+impl<M: CostModel> CostModel for SharedTrack<M> {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        let cost = self.inner.step_cost(height_map, from, to)?;
+        if self.is_shared(from, to) {
+            Some((cost as f64 * self.shared_cost_fraction).ceil() as usize)
+        } else if self.existing[from] {
+            Some(cost.saturating_add(to_cost(self.junction_penalty)))
+        } else {
+            Some(cost)
+        }
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        // The whole way could be along existing track.
+        let fraction = self.shared_cost_fraction.min(1.0);
+        (self.inner.lower_bound(height_map, from, to, connectivity) as f64 * fraction).floor()
+            as usize
+    }
+
+    fn allows_cell(&self, cell: (usize, usize)) -> bool {
+        self.inner.allows_cell(cell)
+    }
+}
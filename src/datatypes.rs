@@ -0,0 +1,553 @@
+// The rasters that the routers work on.
+
+use std::{
+    error::Error,
+    fmt,
+    io::Read,
+    ops::{Index, IndexMut},
+};
+
+use png::{BitDepth, ColorType};
+
+use crate::Connectivity;
+
+/// How the cells and heights of a `HeightMap` relate to the real world.
+///
+/// Route costs stay in cells and height units. The scale only comes in where real
+/// proportions matter, such as the grade of a step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorldScale {
+    /// The width of one cell.
+    pub metres_per_cell: f64,
+    /// The size of one step in height.
+    pub metres_per_height_unit: f64,
+}
+
+impl Default for WorldScale {
+    fn default() -> Self {
+        Self {
+            metres_per_cell: 1.0,
+            metres_per_height_unit: 1.0,
+        }
+    }
+}
+
+/// A value for every cell of a rectangular map, such as a no-go mask or the cost of
+/// entering each cell.
+///
+/// Cells are `(x, y)` pairs with `x < width` and `y < height`. The values are stored
+/// contiguously, row by row, and indexing with a cell outside of the raster panics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Raster<T> {
+    width: usize,
+    height: usize,
+    /// The value of `(x, y)` is at `y * width + x`.
+    values: Vec<T>,
+}
+
+impl<T> Raster<T> {
+    /// A raster from values stored row by row.
+    pub fn new(width: usize, height: usize, values: Vec<T>) -> Self {
+        assert_eq!(
+            values.len(),
+            width * height,
+            "a {}x{} raster needs {} values",
+            width,
+            height,
+            width * height
+        );
+        Self {
+            width,
+            height,
+            values,
+        }
+    }
+
+    /// A raster with the value of every cell given by `value_at`.
+    pub fn from_fn(width: usize, height: usize, value_at: impl FnMut((usize, usize)) -> T) -> Self {
+        let values = cells(width, height).map(value_at).collect();
+        Self::new(width, height, values)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// `(width, height)`.
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Every value, row by row.
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn contains(&self, (x, y): (usize, usize)) -> bool {
+        x < self.width && y < self.height
+    }
+
+    pub fn get(&self, cell: (usize, usize)) -> Option<&T> {
+        self.contains(cell).then(|| &self[cell])
+    }
+
+    /// Every cell, row by row.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, usize)> {
+        cells(self.width, self.height)
+    }
+
+    /// Every cell with its value, row by row.
+    pub fn iter(&self) -> impl Iterator<Item = ((usize, usize), &T)> {
+        self.cells().zip(&self.values)
+    }
+
+    /// A raster of the same size, with `f` applied to every value.
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> Raster<U> {
+        Raster::new(self.width, self.height, self.values.iter().map(f).collect())
+    }
+}
+
+impl<T: Clone> Raster<T> {
+    /// A raster with the same value in every cell.
+    pub fn filled(width: usize, height: usize, value: T) -> Self {
+        Self::new(width, height, vec![value; width * height])
+    }
+}
+
+impl<T> Index<(usize, usize)> for Raster<T> {
+    type Output = T;
+
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        assert!(
+            self.contains((x, y)),
+            "{:?} is outside of the {}x{} raster",
+            (x, y),
+            self.width,
+            self.height
+        );
+        &self.values[y * self.width + x]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Raster<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        assert!(
+            self.contains((x, y)),
+            "{:?} is outside of the {}x{} raster",
+            (x, y),
+            self.width,
+            self.height
+        );
+        &mut self.values[y * self.width + x]
+    }
+}
+
+/// The cells of a `width` by `height` raster, row by row.
+fn cells(width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+    (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+}
+
+/// The terrain height of every cell of a rectangular map.
+///
+/// The heights are a `Raster`, so cells and storage work the same way, and indexing with
+/// a cell outside of the map panics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeightMap {
+    heights: Raster<i32>,
+    pub scale: WorldScale,
+}
+
+impl HeightMap {
+    /// A height map from heights stored row by row, at the default scale.
+    pub fn new(width: usize, height: usize, heights: Vec<i32>) -> Self {
+        Self::from_raster(Raster::new(width, height, heights))
+    }
+
+    /// A height map with the height of every cell given by `height_at`.
+    pub fn from_fn(
+        width: usize,
+        height: usize,
+        height_at: impl FnMut((usize, usize)) -> i32,
+    ) -> Self {
+        Self::from_raster(Raster::from_fn(width, height, height_at))
+    }
+
+    /// A height map from a raster of heights, at the default scale.
+    pub fn from_raster(heights: Raster<i32>) -> Self {
+        Self {
+            heights,
+            scale: WorldScale::default(),
+        }
+    }
+
+    /// A height map from one `Vec` of heights per x, so that `columns[x][y]` is the height
+    /// of `(x, y)`. Every column must be as long as the first.
+    pub fn from_columns(columns: Vec<Vec<i32>>) -> Self {
+        let height = columns.first().map_or(0, Vec::len);
+        assert!(
+            columns.iter().all(|column| column.len() == height),
+            "every column must have the same length"
+        );
+        Self::from_fn(columns.len(), height, |(x, y)| columns[x][y])
+    }
+
+    pub fn with_scale(self, scale: WorldScale) -> Self {
+        Self { scale, ..self }
+    }
+
+    pub fn width(&self) -> usize {
+        self.heights.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.heights.height()
+    }
+
+    /// `(width, height)`.
+    pub fn size(&self) -> (usize, usize) {
+        self.heights.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heights.is_empty()
+    }
+
+    /// Every height, row by row.
+    pub fn heights(&self) -> &[i32] {
+        self.heights.values()
+    }
+
+    pub fn contains(&self, cell: (usize, usize)) -> bool {
+        self.heights.contains(cell)
+    }
+
+    pub fn get(&self, cell: (usize, usize)) -> Option<i32> {
+        self.heights.get(cell).copied()
+    }
+
+    /// Every cell, row by row.
+    pub fn cells(&self) -> impl Iterator<Item = (usize, usize)> {
+        self.heights.cells()
+    }
+
+    /// A raster the size of the map with the same value in every cell, for the extra
+    /// rasters that cost models and searches keep alongside the heights.
+    pub fn raster<T: Clone>(&self, value: T) -> Raster<T> {
+        Raster::filled(self.width(), self.height(), value)
+    }
+
+    /// The cell `offset` away from `cell`, or `None` if that is outside of the map.
+    pub fn offset(&self, (x, y): (usize, usize), (dx, dy): (i32, i32)) -> Option<(usize, usize)> {
+        let cell = (
+            x.checked_add_signed(dx as isize)?,
+            y.checked_add_signed(dy as isize)?,
+        );
+        self.contains(cell).then_some(cell)
+    }
+
+    /// The neighbours of `cell` that lie inside of the map, in the order of
+    /// `connectivity.neighbor_offsets()`.
+    pub fn neighbors(
+        &self,
+        cell: (usize, usize),
+        connectivity: Connectivity,
+    ) -> impl Iterator<Item = (usize, usize)> + '_ {
+        connectivity
+            .neighbor_offsets()
+            .iter()
+            .filter_map(move |&offset| self.offset(cell, offset))
+    }
+
+    /// The height at fractional coordinates, interpolated bilinearly between the four
+    /// surrounding cells, or `None` outside of the map.
+    pub fn sample(&self, x: f64, y: f64) -> Option<f64> {
+        let (width, height) = self.size();
+        if self.is_empty()
+            || !(0.0..=(width - 1) as f64).contains(&x)
+            || !(0.0..=(height - 1) as f64).contains(&y)
+        {
+            return None;
+        }
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (tx, ty) = (x - x0 as f64, y - y0 as f64);
+        let height = |cell| self[cell] as f64;
+        let top = height((x0, y0)) * (1.0 - tx) + height((x1, y0)) * tx;
+        let bottom = height((x0, y1)) * (1.0 - tx) + height((x1, y1)) * tx;
+        Some(top * (1.0 - ty) + bottom * ty)
+    }
+
+    /// Reads a greyscale PNG with a bit depth of 8 or 16 as a height map. Pixel columns run
+    /// along x and pixel rows along y, and every grey level is multiplied by `height_scale`
+    /// and rounded to give the height of its cell. An alpha channel is ignored.
+    ///
+    /// Colour and palette images are rejected rather than guessing which channel holds the
+    /// heights, and so is a `height_scale` that is negative or not finite.
+    pub fn read_png(r: impl Read, height_scale: f64) -> Result<Self, PngError> {
+        if !(height_scale.is_finite() && height_scale >= 0.0) {
+            return Err(PngError::InvalidHeightScale(height_scale));
+        }
+        let mut reader = png::Decoder::new(r).read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buffer)?;
+
+        let channels = match frame.color_type {
+            ColorType::Grayscale => 1,
+            ColorType::GrayscaleAlpha => 2,
+            colour_type => return Err(PngError::UnsupportedColourType(colour_type)),
+        };
+        let bytes = match frame.bit_depth {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+            bit_depth => return Err(PngError::UnsupportedBitDepth(bit_depth)),
+        };
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        let heights = buffer[..frame.buffer_size()]
+            .chunks_exact(channels * bytes)
+            .map(|pixel| {
+                let grey = match bytes {
+                    1 => pixel[0] as u16,
+                    _ => u16::from_be_bytes([pixel[0], pixel[1]]),
+                };
+                (grey as f64 * height_scale).round() as i32
+            })
+            .collect();
+        Ok(Self::new(width, height, heights))
+    }
+
+    /// The rise over run of a straight line from `from` to `to`, in real units under
+    /// `scale`. Positive when climbing.
+    pub fn grade(&self, from: (usize, usize), to: (usize, usize)) -> f64 {
+        let climb = (self[to] - self[from]) as f64 * self.scale.metres_per_height_unit;
+        let run = (from.0.abs_diff(to.0) as f64).hypot(from.1.abs_diff(to.1) as f64)
+            * self.scale.metres_per_cell;
+        climb / run
+    }
+}
+
+/// Why a PNG could not be read as a height map.
+#[derive(Debug)]
+pub enum PngError {
+    /// The file is not a valid PNG, or could not be read.
+    Decoding(png::DecodingError),
+    /// Only greyscale images, with or without alpha, hold one height per pixel.
+    UnsupportedColourType(ColorType),
+    /// Only bit depths of 8 and 16 bits per sample are read.
+    UnsupportedBitDepth(BitDepth),
+    /// The height scale must be finite and not negative.
+    InvalidHeightScale(f64),
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PngError::Decoding(error) => write!(f, "could not decode the PNG: {}", error),
+            PngError::UnsupportedColourType(colour_type) => write!(
+                f,
+                "height maps must be greyscale PNGs, not {:?}",
+                colour_type
+            ),
+            PngError::UnsupportedBitDepth(bit_depth) => write!(
+                f,
+                "height maps must have a bit depth of 8 or 16, not {}",
+                *bit_depth as u8
+            ),
+            PngError::InvalidHeightScale(height_scale) => write!(
+                f,
+                "the height scale must be finite and not negative, not {}",
+                height_scale
+            ),
+        }
+    }
+}
+
+impl Error for PngError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PngError::Decoding(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<png::DecodingError> for PngError {
+    fn from(error: png::DecodingError) -> Self {
+        PngError::Decoding(error)
+    }
+}
+
+impl Index<(usize, usize)> for HeightMap {
+    type Output = i32;
+
+    fn index(&self, cell: (usize, usize)) -> &i32 {
+        &self.heights[cell]
+    }
+}
+
+impl IndexMut<(usize, usize)> for HeightMap {
+    fn index_mut(&mut self, cell: (usize, usize)) -> &mut i32 {
+        &mut self.heights[cell]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_major_storage() {
+        let height_map = HeightMap::from_columns(vec![vec![1, 2, 3], vec![4, 5, 6]]);
+        assert_eq!(height_map.size(), (2, 3));
+        assert_eq!(height_map.heights(), &[1, 4, 2, 5, 3, 6]);
+        assert_eq!(height_map[(1, 2)], 6);
+        assert_eq!(height_map.get((2, 0)), None);
+        assert_eq!(
+            height_map.cells().collect::<Vec<_>>(),
+            vec![(0, 0), (1, 0), (0, 1), (1, 1), (0, 2), (1, 2)]
+        );
+        assert_eq!(
+            HeightMap::from_fn(2, 3, |(x, y)| (3 * x + y + 1) as i32),
+            height_map
+        );
+    }
+
+    #[test]
+    fn rasters_match_the_height_map() {
+        let height_map = HeightMap::new(3, 2, vec![0; 6]);
+        let mut mask = height_map.raster(false);
+        assert_eq!(mask.size(), height_map.size());
+        mask[(2, 1)] = true;
+        assert_eq!(mask.values(), &[false, false, false, false, false, true]);
+        assert_eq!(mask.get((2, 1)), Some(&true));
+        assert_eq!(mask.get((3, 0)), None);
+
+        let raster = Raster::from_fn(3, 2, |(x, y)| x + 10 * y);
+        assert_eq!(raster[(1, 1)], 11);
+        assert_eq!(
+            raster.iter().map(|(cell, &value)| (cell, value)).last(),
+            Some(((2, 1), 12))
+        );
+        assert_eq!(
+            raster.map(|value| value * 2).values(),
+            &[0, 2, 4, 20, 22, 24]
+        );
+    }
+
+    #[test]
+    fn neighbours_stay_inside() {
+        let height_map = HeightMap::new(3, 2, vec![0; 6]);
+        assert_eq!(
+            height_map
+                .neighbors((0, 0), Connectivity::Four)
+                .collect::<Vec<_>>(),
+            vec![(1, 0), (0, 1)]
+        );
+        assert_eq!(height_map.neighbors((1, 1), Connectivity::Eight).count(), 5);
+        assert_eq!(height_map.offset((2, 1), (1, 0)), None);
+        assert_eq!(height_map.offset((2, 1), (-2, -1)), Some((0, 0)));
+    }
+
+    #[test]
+    fn bilinear_sampling_and_grades() {
+        let height_map = HeightMap::from_columns(vec![vec![0, 10], vec![20, 30]]);
+        assert_eq!(height_map.sample(0.0, 0.0), Some(0.0));
+        assert_eq!(height_map.sample(1.0, 1.0), Some(30.0));
+        assert_eq!(height_map.sample(0.5, 0.5), Some(15.0));
+        assert_eq!(height_map.sample(0.25, 1.0), Some(15.0));
+        assert_eq!(height_map.sample(1.5, 0.0), None);
+        assert_eq!(height_map.sample(-0.1, 0.0), None);
+
+        assert_eq!(height_map.grade((0, 0), (1, 0)), 20.0);
+        let scaled = height_map.with_scale(WorldScale {
+            metres_per_cell: 50.0,
+            metres_per_height_unit: 0.5,
+        });
+        assert_eq!(scaled.grade((1, 0), (0, 0)), -0.2);
+    }
+
+    /// Encodes `samples` as a 3x2 PNG.
+    fn encode_png(colour_type: ColorType, bit_depth: BitDepth, samples: &[u8]) -> Vec<u8> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, 3, 2);
+        encoder.set_color(colour_type);
+        encoder.set_depth(bit_depth);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(samples).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    #[test]
+    fn reads_greyscale_pngs() {
+        let png = encode_png(
+            ColorType::Grayscale,
+            BitDepth::Eight,
+            &[0, 1, 2, 10, 20, 255],
+        );
+        let height_map = HeightMap::read_png(&png[..], 1.0).unwrap();
+        assert_eq!(height_map.size(), (3, 2));
+        assert_eq!(height_map.heights(), &[0, 1, 2, 10, 20, 255]);
+        assert_eq!(height_map[(2, 1)], 255);
+
+        // 16-bit samples are big-endian, and keep their full range.
+        let samples = [0u16, 1, 256, 1000, 40000, 65535]
+            .iter()
+            .flat_map(|sample| sample.to_be_bytes())
+            .collect::<Vec<_>>();
+        let png = encode_png(ColorType::Grayscale, BitDepth::Sixteen, &samples);
+        let height_map = HeightMap::read_png(&png[..], 0.1).unwrap();
+        assert_eq!(height_map.heights(), &[0, 0, 26, 100, 4000, 6554]);
+
+        let png = encode_png(
+            ColorType::GrayscaleAlpha,
+            BitDepth::Eight,
+            &[5, 255, 6, 0, 7, 128, 8, 255, 9, 255, 10, 255],
+        );
+        let height_map = HeightMap::read_png(&png[..], 2.0).unwrap();
+        assert_eq!(height_map.heights(), &[10, 12, 14, 16, 18, 20]);
+    }
+
+    #[test]
+    fn rejects_other_pngs() {
+        let png = encode_png(ColorType::Rgb, BitDepth::Eight, &[0; 3 * 2 * 3]);
+        assert!(matches!(
+            HeightMap::read_png(&png[..], 1.0),
+            Err(PngError::UnsupportedColourType(ColorType::Rgb))
+        ));
+        let png = encode_png(ColorType::Grayscale, BitDepth::Four, &[0; 2 * 2]);
+        assert!(matches!(
+            HeightMap::read_png(&png[..], 1.0),
+            Err(PngError::UnsupportedBitDepth(BitDepth::Four))
+        ));
+        assert!(matches!(
+            HeightMap::read_png(&b"not a png"[..], 1.0),
+            Err(PngError::Decoding(_))
+        ));
+        let png = encode_png(ColorType::Grayscale, BitDepth::Eight, &[0; 3 * 2]);
+        for height_scale in [f64::NAN, f64::INFINITY, -1.0] {
+            assert!(matches!(
+                HeightMap::read_png(&png[..], height_scale),
+                Err(PngError::InvalidHeightScale(_))
+            ));
+        }
+
+        // The PNG next to the test height map is in colour.
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/resources/test/height_map_256_256.png"
+        );
+        let error = HeightMap::read_png(std::fs::File::open(path).unwrap(), 1.0).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "height maps must be greyscale PNGs, not Rgba"
+        );
+    }
+}
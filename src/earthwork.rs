@@ -0,0 +1,460 @@
+// Cut and fill: how much ground has to be dug out or piled up to lay track at a smoothed
+// elevation instead of on the bare terrain.
+
+use crate::{
+    a_star_with_stats,
+    cost_model::{distance, to_cost, CostModel},
+    datatypes::{HeightMap, Raster},
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Route, RoutingError, SearchStats,
+};
+
+/// The cut and fill volumes for every cell of a path, in cubic metres.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Earthwork {
+    /// Ground dug out where the terrain is above the track.
+    pub cut: Vec<f64>,
+    /// Ground piled up where the terrain is below the track.
+    pub fill: Vec<f64>,
+}
+
+impl Earthwork {
+    pub fn total_cut(&self) -> f64 {
+        self.cut.iter().sum()
+    }
+
+    pub fn total_fill(&self) -> f64 {
+        self.fill.iter().sum()
+    }
+
+    /// Positive when there is more cut than fill, i.e. spoil to haul away.
+    pub fn net(&self) -> f64 {
+        self.total_cut() - self.total_fill()
+    }
+}
+
+/// Computes the cut and fill needed to lay the track along `path` at `profile`, which has
+/// one track elevation for every cell of `path`.
+///
+/// The track bed is `formation_width` metres wide with vertical sides, so each cell's
+/// volume is the depth times the track length in it times the width, converted to metres
+/// with the height map's `WorldScale`. Each cell is charged for half of the steps on
+/// either side of it, so diagonal steps count for their full length. `path` should be
+/// made of neighbouring cells, so any-angle paths should go through `route_cells` first.
+///
+/// Returns `RoutingError::InvalidSetting` if `profile` isn't as long as `path`.
+pub fn earthwork(
+    path: &[(usize, usize)],
+    profile: &[f64],
+    height_map: &HeightMap,
+    formation_width: f64,
+) -> Result<Earthwork, RoutingError> {
+    if path.len() != profile.len() {
+        return Err(RoutingError::InvalidSetting {
+            name: "profile",
+            reason: "must have one elevation per cell of the path",
+        });
+    }
+
+    let lengths = (0..path.len()).map(|i| {
+        let before = i.checked_sub(1).map_or(0.0, |j| distance(path[j], path[i]));
+        let after = path.get(i + 1).map_or(0.0, |&next| distance(path[i], next));
+        (before + after) / 2.0
+    });
+
+    let (cut, fill) = path
+        .iter()
+        .zip(profile)
+        .zip(lengths)
+        .map(|((&cell, &elevation), length)| {
+            volumes(
+                height_map,
+                height_map[cell] as f64 - elevation,
+                length,
+                formation_width,
+            )
+        })
+        .unzip();
+    Ok(Earthwork { cut, fill })
+}
+
+/// The cut and fill in cubic metres for `length` cells of track bed `formation_width`
+/// metres wide, laid `depth` height units below the terrain (negative when above it).
+fn volumes(height_map: &HeightMap, depth: f64, length: f64, formation_width: f64) -> (f64, f64) {
+    let volume = |depth: f64| {
+        depth.max(0.0)
+            * height_map.scale.metres_per_height_unit
+            * formation_width
+            * length
+            * height_map.scale.metres_per_cell
+    };
+    (volume(depth), volume(-depth))
+}
+
+/// A track elevation profile for `path`: the terrain height averaged over up to `radius`
+/// cells before and after each cell.
+pub fn smoothed_profile(
+    path: &[(usize, usize)],
+    height_map: &HeightMap,
+    radius: usize,
+) -> Vec<f64> {
+    let heights = path
+        .iter()
+        .map(|&(x, y)| height_map[(x, y)] as f64)
+        .collect::<Vec<_>>();
+    (0..heights.len())
+        .map(|i| {
+            let window = &heights[i.saturating_sub(radius)..(i + radius + 1).min(heights.len())];
+            window.iter().sum::<f64>() / window.len() as f64
+        })
+        .collect()
+}
+
+/// The terrain height averaged over the square of cells up to `radius` away, as an
+/// estimate of where the track will run before its route is known.
+pub fn smoothed_terrain(height_map: &HeightMap, radius: usize) -> Raster<f64> {
+    let (x_len, y_len) = height_map.size();
+    Raster::from_fn(x_len, y_len, |(x, y)| {
+        let xs = x.saturating_sub(radius)..(x + radius + 1).min(x_len);
+        let ys = y.saturating_sub(radius)..(y + radius + 1).min(y_len);
+        let count = xs.len() * ys.len();
+        let sum = xs
+            .flat_map(|x| ys.clone().map(move |y| height_map[(x, y)] as f64))
+            .sum::<f64>();
+        sum / count as f64
+    })
+}
+
+/// Charges for cut and fill on top of another model, against a formation raster of track
+/// elevations (usually from `smoothed_terrain`).
+///
+/// A step costs `cut_cost` per cubic metre of cut and `fill_cost` per cubic metre of fill,
+/// in units of `COST_PER_CELL`. The volumes are worked out like `earthwork` does, for a
+/// track bed `formation_width` metres wide, with each end of the step carrying half of
+/// its length. So the earthwork part of a route's cost is what `earthwork` reports for it
+/// against the formation, up to rounding.
+#[derive(Debug, Clone)]
+pub struct EarthworkCost<M> {
+    pub inner: M,
+    pub formation: Raster<f64>,
+    pub formation_width: f64,
+    pub cut_cost: f64,
+    pub fill_cost: f64,
+}
+
+impl<M: CostModel> CostModel for EarthworkCost<M> {
+    fn step_cost(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+    ) -> Option<usize> {
+        let half = distance(from, to) / 2.0;
+        let cost = [from, to]
+            .into_iter()
+            .map(|cell| {
+                let depth = height_map[cell] as f64 - self.formation[cell];
+                let (cut, fill) = volumes(height_map, depth, half, self.formation_width);
+                self.cut_cost * cut + self.fill_cost * fill
+            })
+            .sum::<f64>();
+        Some(
+            self.inner
+                .step_cost(height_map, from, to)?
+                .saturating_add(to_cost(cost)),
+        )
+    }
+
+    fn lower_bound(
+        &self,
+        height_map: &HeightMap,
+        from: (usize, usize),
+        to: (usize, usize),
+        connectivity: Connectivity,
+    ) -> usize {
+        self.inner.lower_bound(height_map, from, to, connectivity)
+    }
+
+    fn allows_cell(&self, cell: (usize, usize)) -> bool {
+        self.inner.allows_cell(cell)
+    }
+}
+
+/// Settings for `balanced_earthwork_route`.
+#[derive(Debug, Clone, Copy)]
+pub struct Balancing {
+    /// The formation is the terrain smoothed over this many cells, see `smoothed_terrain`.
+    pub radius: usize,
+    /// The width of the track bed, in metres.
+    pub formation_width: f64,
+    /// The average cost of a cubic metre of cut or fill, in units of `COST_PER_CELL`.
+    pub volume_cost: f64,
+    /// How many times to route while adjusting the split between cut and fill costs.
+    pub iterations: usize,
+}
+
+/// Routes from `start` to `end` under `cost_model` plus the cost of earthwork against a
+/// smoothed formation, while trying to balance cut against fill so that the spoil from
+/// cuttings can go into embankments.
+///
+/// The split of `balancing.volume_cost` between cut and fill is adjusted by bisection:
+/// the more cut a route has, the more expensive cut gets. Returns the route with the
+/// smallest imbalance, along with its earthwork against the formation.
+pub fn balanced_earthwork_route(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    balancing: Balancing,
+) -> Result<(Route, Earthwork), RoutingError> {
+    balanced_earthwork_route_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        balancing,
+        &mut NoObserver,
+    )
+}
+
+/// Like `balanced_earthwork_route`, but reports the progress of all the iterations
+/// together to `observer`, which may cancel them.
+pub fn balanced_earthwork_route_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    balancing: Balancing,
+    observer: &mut impl SearchObserver,
+) -> Result<(Route, Earthwork), RoutingError> {
+    let mut stats = SearchStats::default();
+    let volume_cost = balancing.volume_cost;
+    let mut model = EarthworkCost {
+        inner: cost_model,
+        formation: smoothed_terrain(height_map, balancing.radius),
+        formation_width: balancing.formation_width,
+        cut_cost: volume_cost,
+        fill_cost: volume_cost,
+    };
+
+    // How much more cut costs than fill, from -1 (only fill costs) to 1 (only cut costs).
+    let (mut low, mut high) = (-1.0, 1.0);
+    let mut best: Option<(Route, Earthwork)> = None;
+    for _ in 0..balancing.iterations.max(1) {
+        let skew = (low + high) / 2.0;
+        model.cut_cost = volume_cost * (1.0 + skew);
+        model.fill_cost = volume_cost * (1.0 - skew);
+
+        let route = a_star_with_stats(
+            start,
+            end,
+            height_map,
+            &model,
+            connectivity,
+            &mut stats,
+            observer,
+        )?;
+        let profile = route
+            .path
+            .iter()
+            .map(|&cell| model.formation[cell])
+            .collect::<Vec<_>>();
+        let earthwork = earthwork(&route.path, &profile, height_map, model.formation_width)?;
+
+        if earthwork.net() > 0.0 {
+            low = skew;
+        } else {
+            high = skew;
+        }
+        if best
+            .as_ref()
+            .is_none_or(|(_, best)| earthwork.net().abs() < best.net().abs())
+        {
+            best = Some((route, earthwork));
+        }
+    }
+    Ok(best.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        a_star,
+        cost_model::{FlatDistance, COST_PER_CELL},
+        datatypes::WorldScale,
+    };
+
+    #[test]
+    fn cut_and_fill_per_cell() {
+        let mut height_map = HeightMap::new(5, 3, vec![0; 5 * 3]);
+        height_map[(1, 0)] = 3;
+        height_map[(3, 0)] = -2;
+        let path = (0..5).map(|x| (x, 0)).collect::<Vec<_>>();
+
+        let work = earthwork(&path, &[0.0; 5], &height_map, 1.0).unwrap();
+        assert_eq!(work.cut, vec![0.0, 3.0, 0.0, 0.0, 0.0]);
+        assert_eq!(work.fill, vec![0.0, 0.0, 0.0, 2.0, 0.0]);
+        assert_eq!(work.net(), 1.0);
+
+        // The ends only carry half a step.
+        let work = earthwork(&path, &[-1.0; 5], &height_map, 1.0).unwrap();
+        assert_eq!(work.cut, vec![0.5, 4.0, 1.0, 0.0, 0.5]);
+        assert_eq!(work.total_fill(), 1.0);
+    }
+
+    #[test]
+    fn volumes_follow_world_scale_and_width() {
+        let height_map = HeightMap::new(3, 1, vec![0, 3, 0]).with_scale(WorldScale {
+            metres_per_cell: 10.0,
+            metres_per_height_unit: 0.5,
+        });
+        let path = [(0, 0), (1, 0), (2, 0)];
+
+        // 1.5 m deep, 10 m long and 4 m wide.
+        let work = earthwork(&path, &[0.0; 3], &height_map, 4.0).unwrap();
+        assert_eq!(work.cut, vec![0.0, 60.0, 0.0]);
+        assert_eq!(work.total_fill(), 0.0);
+
+        let cost_model = EarthworkCost {
+            inner: FlatDistance,
+            formation: height_map.raster(0.0),
+            formation_width: 4.0,
+            cut_cost: 0.5,
+            fill_cost: 2.0,
+        };
+        // The steps into and out of the cutting carry half of it each.
+        assert_eq!(
+            cost_model.step_cost(&height_map, (0, 0), (1, 0)),
+            Some((1.0 + 0.5 * 30.0) as usize * COST_PER_CELL)
+        );
+    }
+
+    #[test]
+    fn smoothing_cuts_peaks_and_fills_dips() {
+        let height_map = HeightMap::new(5, 1, vec![0, 0, 6, 0, 0]);
+        let path = (0..5).map(|x| (x, 0)).collect::<Vec<_>>();
+
+        let profile = smoothed_profile(&path, &height_map, 1);
+        assert_eq!(profile, vec![0.0, 2.0, 2.0, 2.0, 0.0]);
+        let work = earthwork(&path, &profile, &height_map, 1.0).unwrap();
+        assert_eq!(work.total_cut(), 4.0);
+        assert_eq!(work.total_fill(), 4.0);
+
+        assert_eq!(smoothed_terrain(&height_map, 1)[(2, 0)], 2.0);
+    }
+
+    #[test]
+    fn routed_cost_matches_the_earthwork() {
+        let height_map = HeightMap::from_fn(20, 20, |(x, y)| ((x * 7 + y * 3) % 5) as i32)
+            .with_scale(WorldScale {
+                metres_per_cell: 5.0,
+                metres_per_height_unit: 0.5,
+            });
+        let cost_model = EarthworkCost {
+            inner: FlatDistance,
+            formation: smoothed_terrain(&height_map, 2),
+            formation_width: 3.0,
+            cut_cost: 0.2,
+            fill_cost: 0.3,
+        };
+        let (route, _) = a_star(
+            (0, 0),
+            (19, 13),
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            |_, _, _| 0,
+        )
+        .unwrap();
+
+        let profile = route
+            .path
+            .iter()
+            .map(|&cell| cost_model.formation[cell])
+            .collect::<Vec<_>>();
+        let work = earthwork(&route.path, &profile, &height_map, 3.0).unwrap();
+        let flat_cost = route
+            .path
+            .windows(2)
+            .map(|step| {
+                FlatDistance
+                    .step_cost(&height_map, step[0], step[1])
+                    .unwrap()
+            })
+            .sum::<usize>();
+        let earthwork_cost = (route.cost - flat_cost) as f64 / COST_PER_CELL as f64;
+        let expected = 0.2 * work.total_cut() + 0.3 * work.total_fill();
+        // Every step rounds its cost up by less than one fixed point unit.
+        assert!(expected > 1.0);
+        assert!(earthwork_cost >= expected - 1e-9);
+        assert!(
+            earthwork_cost <= expected + route.path.len() as f64 / COST_PER_CELL as f64,
+            "{} vs {}",
+            earthwork_cost,
+            expected
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_profiles() {
+        let height_map = HeightMap::new(3, 1, vec![0; 3]);
+        assert_eq!(
+            earthwork(&[(0, 0), (1, 0)], &[0.0], &height_map, 1.0),
+            Err(RoutingError::InvalidSetting {
+                name: "profile",
+                reason: "must have one elevation per cell of the path",
+            })
+        );
+    }
+
+    #[test]
+    fn balancing_reduces_imbalance() {
+        // A hill on the direct line, so that the cheapest route is mostly cut.
+        let height_map = HeightMap::from_fn(30, 30, |(x, y)| {
+            (12 - (x as i32 - 15).abs() - (y as i32 - 15).abs()).max(0)
+        });
+        let start = (0, 15);
+        let end = (29, 15);
+
+        let (unbalanced, unbalanced_work) = balanced_earthwork_route(
+            start,
+            end,
+            &height_map,
+            &FlatDistance,
+            Connectivity::Eight,
+            Balancing {
+                radius: 3,
+                formation_width: 1.0,
+                volume_cost: 0.5,
+                iterations: 1,
+            },
+        )
+        .unwrap();
+        let (balanced, balanced_work) = balanced_earthwork_route(
+            start,
+            end,
+            &height_map,
+            &FlatDistance,
+            Connectivity::Eight,
+            Balancing {
+                radius: 3,
+                formation_width: 1.0,
+                volume_cost: 0.5,
+                iterations: 8,
+            },
+        )
+        .unwrap();
+
+        for route in [&unbalanced, &balanced] {
+            assert_eq!(route.path.first(), Some(&start));
+            assert_eq!(route.path.last(), Some(&end));
+        }
+        assert_eq!(balanced_work.cut.len(), balanced.path.len());
+        assert!(unbalanced_work.net() > 1.0);
+        assert!(balanced_work.net().abs() < unbalanced_work.net().abs());
+    }
+}
@@ -0,0 +1,338 @@
+// Routing that keeps track of the direction of travel, so that the track can't turn
+// more sharply than a train can.
+
+use std::f64::consts::PI;
+
+use crate::{
+    best_first_search, check_bounds,
+    cost_model::{distance, CostModel, COST_PER_CELL},
+    datatypes::HeightMap,
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Relaxation, Route, RoutingError, SearchStats,
+};
+
+/// The 8 directions of travel, ordered so that neighbouring entries are 45° apart.
+const HEADINGS: [(i32, i32); 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+
+/// How sharply the track may turn.
+///
+/// A curve of radius `r` is approximated by a series of turns of at most
+/// `max_turn_degrees`, separated by straight runs of at least `min_turn_spacing`.
+#[derive(Debug, Clone, Copy)]
+pub struct CurveLimit {
+    /// The largest change of heading allowed at a single cell, in degrees.
+    /// Headings come in steps of 45°, so anything below 45 forbids turning at all.
+    pub max_turn_degrees: f64,
+    /// How far the track must run straight after a turn before it may turn again, in cells.
+    pub min_turn_spacing: f64,
+}
+
+impl CurveLimit {
+    /// Approximates a minimum curve radius, in cells, with 45° turns:
+    /// an arc of radius `r` turns by 45° every `r * π / 4` cells.
+    pub fn from_radius(radius: f64) -> Self {
+        Self {
+            max_turn_degrees: 45.0,
+            min_turn_spacing: radius * PI / 4.0,
+        }
+    }
+
+    fn allows(&self, from_heading: usize, to_heading: usize, since_turn: usize) -> bool {
+        let turns = (from_heading + HEADINGS.len() - to_heading) % HEADINGS.len();
+        let turns = turns.min(HEADINGS.len() - turns);
+        turns == 0
+            || (turns as f64 * 45.0 <= self.max_turn_degrees
+                && since_turn >= self.min_turn_spacing_cost())
+    }
+
+    /// `min_turn_spacing` in the same fixed point units as step costs.
+    fn min_turn_spacing_cost(&self) -> usize {
+        (self.min_turn_spacing * COST_PER_CELL as f64).ceil() as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct HeadingState {
+    position: (usize, usize),
+    /// Index into `HEADINGS`, or `None` before the first step.
+    heading: Option<usize>,
+    /// Distance travelled since the last turn, in units of `COST_PER_CELL`.
+    /// This is capped at the turn spacing, so that all states which may turn again are merged.
+    since_turn: usize,
+}
+
+/// 8-connected A* search from `start` to `end` whose paths respect `curve_limit`.
+///
+/// The search state includes the heading and the distance since the last turn, so this
+/// explores several times more states than `a_star`. The initial heading is free.
+///
+/// A state is only its cell, heading and distance since the last turn, so the route is
+/// the cheapest one under `curve_limit`, and one is found whenever any exists. That route
+/// may run through a cell twice (crossing itself, say) where looping round is the cheapest
+/// way to turn.
+pub fn curve_limited_a_star(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    curve_limit: CurveLimit,
+) -> Result<(Route, SearchStats), RoutingError> {
+    curve_limited_a_star_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        curve_limit,
+        &mut NoObserver,
+    )
+}
+
+/// Like `curve_limited_a_star`, but reports its progress to `observer`, which may cancel
+/// the search. Every expanded state counts as an expanded position.
+pub fn curve_limited_a_star_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    curve_limit: CurveLimit,
+    observer: &mut impl SearchObserver,
+) -> Result<(Route, SearchStats), RoutingError> {
+    check_bounds(height_map, &[start, end])?;
+    let spacing = curve_limit.min_turn_spacing_cost();
+
+    let mut stats = SearchStats::default();
+    let result = best_first_search(
+        HeadingState {
+            position: start,
+            heading: None,
+            since_turn: spacing,
+        },
+        |state| state.position == end,
+        |state| cost_model.lower_bound(height_map, state.position, end, Connectivity::Eight),
+        |curr, relaxations| {
+            let curr_state = curr.position;
+            for (heading, &offset) in HEADINGS.iter().enumerate() {
+                let since_turn = match curr_state.heading {
+                    None => spacing,
+                    Some(curr_heading) if curr_heading == heading => curr_state.since_turn,
+                    Some(curr_heading) => {
+                        if !curve_limit.allows(curr_heading, heading, curr_state.since_turn) {
+                            continue;
+                        }
+                        0
+                    }
+                };
+                let from = curr_state.position;
+                let Some(to) = height_map.offset(from, offset) else {
+                    continue;
+                };
+                let Some(step_cost) = cost_model.step_cost(height_map, from, to) else {
+                    continue;
+                };
+                let step_length = (distance(from, to) * COST_PER_CELL as f64).round() as usize;
+                relaxations.push(Relaxation {
+                    position: HeadingState {
+                        position: to,
+                        heading: Some(heading),
+                        since_turn: (since_turn + step_length).min(spacing),
+                    },
+                    from: curr_state,
+                    step_cost,
+                });
+            }
+        },
+        true,
+        &mut stats,
+        observer,
+    )?;
+    let Some((path, cost)) = result else {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    };
+
+    Ok((
+        Route {
+            path: path.into_iter().map(|state| state.position).collect(),
+            cost,
+        },
+        stats,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cost_model::{AbsoluteClimb, FlatDistance, NoGo},
+        datatypes::Raster,
+        dijkstra,
+    };
+
+    /// Checks that every turn along `path` is allowed by `curve_limit`.
+    fn assert_respects_curve_limit(path: &[(usize, usize)], curve_limit: CurveLimit) {
+        let headings = path
+            .windows(2)
+            .map(|step| {
+                let offset = (
+                    step[1].0 as i32 - step[0].0 as i32,
+                    step[1].1 as i32 - step[0].1 as i32,
+                );
+                let heading = HEADINGS.iter().position(|&h| h == offset);
+                heading.unwrap_or_else(|| panic!("{:?} is not 8-connected", step))
+            })
+            .collect::<Vec<_>>();
+
+        let mut since_turn = curve_limit.min_turn_spacing_cost();
+        for (i, pair) in headings.windows(2).enumerate() {
+            let step_length = (distance(path[i], path[i + 1]) * COST_PER_CELL as f64).round();
+            since_turn += step_length as usize;
+            if pair[0] != pair[1] {
+                assert!(
+                    curve_limit.allows(pair[0], pair[1], since_turn),
+                    "turn at {:?} breaks {:?}",
+                    path[i + 1],
+                    curve_limit
+                );
+                since_turn = 0;
+            }
+        }
+    }
+
+    #[test]
+    fn flat_ground_turns_gradually() {
+        let height_map = HeightMap::new(30, 30, vec![0; 30 * 30]);
+        let curve_limit = CurveLimit::from_radius(4.0);
+
+        let start = (0, 0);
+        let end = (29, 10);
+        let (route, _) =
+            curve_limited_a_star(start, end, &height_map, &FlatDistance, curve_limit).unwrap();
+
+        assert_eq!(route.path.first(), Some(&start));
+        assert_eq!(route.path.last(), Some(&end));
+        assert_respects_curve_limit(&route.path, curve_limit);
+    }
+
+    #[test]
+    fn no_turns_allowed() {
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let curve_limit = CurveLimit {
+            max_turn_degrees: 0.0,
+            min_turn_spacing: 0.0,
+        };
+
+        assert!(
+            curve_limited_a_star((0, 0), (9, 9), &height_map, &FlatDistance, curve_limit).is_ok()
+        );
+        assert_eq!(
+            curve_limited_a_star((0, 0), (9, 3), &height_map, &FlatDistance, curve_limit),
+            Err(RoutingError::NoFeasibleRoute {
+                start: (0, 0),
+                end: (9, 3)
+            })
+        );
+    }
+
+    #[test]
+    fn rough_terrain_respects_curve_limit() {
+        // A deterministic but bumpy 40x40 terrain.
+        let height_map = HeightMap::from_fn(40, 40, |(x, y)| (x as i32 * 7 + y as i32 * 13) % 11);
+        let curve_limit = CurveLimit {
+            max_turn_degrees: 45.0,
+            min_turn_spacing: 3.0,
+        };
+
+        let (route, _) = curve_limited_a_star(
+            (0, 0),
+            (39, 39),
+            &height_map,
+            &AbsoluteClimb::default(),
+            curve_limit,
+        )
+        .unwrap();
+        assert_respects_curve_limit(&route.path, curve_limit);
+    }
+
+    #[test]
+    fn unlimited_turns_match_dijkstra() {
+        // Bumpy terrain with scattered no-go cells, where turning around in a loop is tempting.
+        let mut state = 0x1234_5678_9abc_def1_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..200 {
+            let size = 12 + (next() % 8) as usize;
+            let height_map = HeightMap::from_fn(size, size, |_| (next() % 6) as i32);
+            let mut mask = Raster::from_fn(size, size, |_| next() % 4 == 0);
+            let start = (
+                (next() % size as u64) as usize,
+                (next() % size as u64) as usize,
+            );
+            let end = (
+                (next() % size as u64) as usize,
+                (next() % size as u64) as usize,
+            );
+            mask[start] = false;
+            mask[end] = false;
+            let cost_model = NoGo {
+                inner: AbsoluteClimb::default(),
+                mask,
+            };
+            let unlimited = CurveLimit {
+                max_turn_degrees: 180.0,
+                min_turn_spacing: 0.0,
+            };
+
+            // Any turn is allowed, so the curve-limited search finds exactly the routes
+            // that plain Dijkstra finds, at the same cost.
+            let expected = dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight);
+            let route = curve_limited_a_star(start, end, &height_map, &cost_model, unlimited)
+                .map(|(route, _)| route);
+            assert_eq!(
+                route.as_ref().map(|route| route.cost),
+                expected.as_ref().map(|route| route.cost),
+                "from {:?} to {:?}",
+                start,
+                end
+            );
+            if let Ok(route) = route {
+                assert_eq!(route.path.first(), Some(&start));
+                assert_eq!(route.path.last(), Some(&end));
+            }
+        }
+    }
+
+    #[test]
+    fn limited_turns_still_find_a_route() {
+        // The only way from the start to the end is around the far end of a wall, so the
+        // route has to turn right round within the curve limit.
+        let height_map = HeightMap::new(20, 20, vec![0; 20 * 20]);
+        let cost_model = NoGo {
+            inner: FlatDistance,
+            mask: Raster::from_fn(20, 20, |(x, y)| y == 10 && x < 17),
+        };
+        let curve_limit = CurveLimit::from_radius(2.0);
+        let (start, end) = ((2, 12), (2, 8));
+
+        let (route, _) =
+            curve_limited_a_star(start, end, &height_map, &cost_model, curve_limit).unwrap();
+        assert_eq!(route.path.first(), Some(&start));
+        assert_eq!(route.path.last(), Some(&end));
+        assert!(route.path.iter().all(|&cell| !cost_model.mask[cell]));
+        assert_respects_curve_limit(&route.path, curve_limit);
+
+        let shortest = dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight).unwrap();
+        assert!(route.cost >= shortest.cost);
+    }
+}
@@ -0,0 +1,590 @@
+// HPA*-style hierarchical routing: the height map is split into square clusters, and
+// searches run over a small graph of cluster entrances before being refined cell by cell.
+
+use std::collections::HashMap;
+
+use crate::{
+    best_first_search, check_bounds,
+    cost_model::CostModel,
+    datatypes::HeightMap,
+    expand_cell, grow_search_tree,
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Expansion, Relaxation, Route, RoutingError, SearchStats, SearchTree,
+};
+
+/// A route found by `HierarchicalRouter`, which may be more expensive than the exact one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HierarchicalRoute {
+    pub route: Route,
+    /// The exact route costs at least this much.
+    pub lower_bound: usize,
+}
+
+impl HierarchicalRoute {
+    /// How many times more expensive than the exact route this route can be, at most.
+    ///
+    /// This comes from the cost model's `lower_bound`, so it is only tight for cost models
+    /// with good lower bounds, such as `FlatDistance` and `AbsoluteClimb`.
+    /// Returns `None` if the lower bound is 0 but the route isn't free, since then there
+    /// is no bound.
+    pub fn suboptimality_bound(&self) -> Option<f64> {
+        match (self.route.cost, self.lower_bound) {
+            (0, _) => Some(1.0),
+            (_, 0) => None,
+            (cost, lower_bound) => Some(cost as f64 / lower_bound as f64),
+        }
+    }
+}
+
+/// A step of the abstract graph: the entrance cell it leads to, and its cost.
+type GraphStep = ((usize, usize), usize);
+
+/// Two cells facing each other across the border between clusters.
+type Crossing = ((usize, usize), (usize, usize));
+
+/// Routes over an abstract graph of cluster entrances, which is built once per height map
+/// and cost model.
+///
+/// Every pair of neighbouring clusters is joined by one or two entrances per open stretch
+/// of their shared border, and the entrances of each cluster are joined by the cost of the
+/// cheapest path between them that stays inside the cluster. A query only searches this
+/// graph, then refines each abstract step with a search confined to a single cluster.
+///
+/// Routes must cross between clusters at an entrance, and can't leave a cluster and come
+/// back between two of its entrances, which is where the suboptimality comes from.
+pub struct HierarchicalRouter<'a, M> {
+    height_map: &'a HeightMap,
+    cost_model: M,
+    connectivity: Connectivity,
+    layout: ClusterLayout,
+    /// The entrance cells of every cluster.
+    entrances: HashMap<(usize, usize), Vec<(usize, usize)>>,
+    /// The abstract graph: the steps out of every entrance cell, with their costs.
+    edges: HashMap<(usize, usize), Vec<GraphStep>>,
+}
+
+/// How the height map is split up for `HierarchicalRouter`.
+#[derive(Debug, Clone, Copy)]
+pub struct ClusterLayout {
+    /// Clusters are squares of this many cells per side.
+    pub size: usize,
+    /// Open stretches of a border get an entrance at each end and then one every this
+    /// many cells, or a single one in the middle if they are no longer than this.
+    /// More entrances make routes closer to exact, at the cost of a bigger abstract graph.
+    pub entrance_spacing: usize,
+}
+
+impl<'a, M: CostModel> HierarchicalRouter<'a, M> {
+    /// Builds the abstract graph for `height_map`.
+    ///
+    /// `Connectivity::AnyAngle` is treated like `Connectivity::Eight`, since straight
+    /// segments could leave the cluster they are refined in.
+    /// Returns `RoutingError::InvalidSetting` if the cluster size or entrance spacing is 0.
+    pub fn new(
+        height_map: &'a HeightMap,
+        cost_model: M,
+        connectivity: Connectivity,
+        layout: ClusterLayout,
+    ) -> Result<Self, RoutingError> {
+        Self::new_observed(
+            height_map,
+            cost_model,
+            connectivity,
+            layout,
+            &mut NoObserver,
+        )
+    }
+
+    /// Like `new`, but reports the progress of the searches inside the clusters together
+    /// to `observer`, which may cancel them.
+    pub fn new_observed(
+        height_map: &'a HeightMap,
+        cost_model: M,
+        connectivity: Connectivity,
+        layout: ClusterLayout,
+        observer: &mut impl SearchObserver,
+    ) -> Result<Self, RoutingError> {
+        if layout.size == 0 {
+            return Err(RoutingError::InvalidSetting {
+                name: "cluster size",
+                reason: "must be at least 1",
+            });
+        }
+        if layout.entrance_spacing == 0 {
+            return Err(RoutingError::InvalidSetting {
+                name: "entrance spacing",
+                reason: "must be at least 1",
+            });
+        }
+        let (x_len, y_len) = check_bounds(height_map, &[])?;
+        let connectivity = match connectivity {
+            Connectivity::AnyAngle => Connectivity::Eight,
+            connectivity => connectivity,
+        };
+
+        let mut router = Self {
+            height_map,
+            cost_model,
+            connectivity,
+            layout,
+            entrances: HashMap::new(),
+            edges: HashMap::new(),
+        };
+
+        let cluster_size = layout.size;
+        // The borders between clusters that are next to each other in x, then in y.
+        for x in (cluster_size..x_len).step_by(cluster_size) {
+            for y_start in (0..y_len).step_by(cluster_size) {
+                let border = (y_start..y_len.min(y_start + cluster_size))
+                    .map(|y| ((x - 1, y), (x, y)))
+                    .collect::<Vec<_>>();
+                router.add_entrances(&border);
+            }
+        }
+        for y in (cluster_size..y_len).step_by(cluster_size) {
+            for x_start in (0..x_len).step_by(cluster_size) {
+                let border = (x_start..x_len.min(x_start + cluster_size))
+                    .map(|x| ((x, y - 1), (x, y)))
+                    .collect::<Vec<_>>();
+                router.add_entrances(&border);
+            }
+        }
+
+        let mut cluster_edges = Vec::new();
+        let mut stats = SearchStats::default();
+        for entrances in router.entrances.values() {
+            for &from in entrances {
+                let tree = router.cluster_tree(from, entrances, &mut stats, observer)?;
+                for &to in entrances {
+                    if let (true, Some(cost)) = (to != from, tree.cost(to)) {
+                        cluster_edges.push((from, (to, cost)));
+                    }
+                }
+            }
+        }
+        for (from, edge) in cluster_edges {
+            router.edges.entry(from).or_default().push(edge);
+        }
+
+        Ok(router)
+    }
+
+    fn cluster(&self, cell: (usize, usize)) -> (usize, usize) {
+        (cell.0 / self.layout.size, cell.1 / self.layout.size)
+    }
+
+    /// Adds entrances along a border, given as pairs of cells facing each other across it.
+    fn add_entrances(&mut self, border: &[Crossing]) {
+        let height_map = self.height_map;
+        let is_open = |&(a, b): &Crossing| {
+            self.cost_model.step_cost(height_map, a, b).is_some()
+                && self.cost_model.step_cost(height_map, b, a).is_some()
+        };
+
+        let spacing = self.layout.entrance_spacing;
+        let mut crossings = Vec::new();
+        for stretch in border.split(|pair| !is_open(pair)) {
+            match stretch.len() {
+                0 => {}
+                len if len <= spacing => crossings.push(stretch[len / 2]),
+                len => {
+                    crossings.extend(stretch.iter().step_by(spacing));
+                    if (len - 1) % spacing != 0 {
+                        crossings.push(stretch[len - 1]);
+                    }
+                }
+            }
+        }
+
+        for (a, b) in crossings {
+            for (from, to) in [(a, b), (b, a)] {
+                let cost = self.cost_model.step_cost(height_map, from, to).unwrap();
+                self.edges.entry(from).or_default().push((to, cost));
+                let entrances = self.entrances.entry(self.cluster(from)).or_default();
+                if !entrances.contains(&from) {
+                    entrances.push(from);
+                }
+            }
+        }
+    }
+
+    /// Pushes the ways of reaching the neighbours of a cell that stay inside `cluster`.
+    fn expand_in_cluster(
+        &self,
+        cluster: (usize, usize),
+        curr: Expansion<(usize, usize)>,
+        relaxations: &mut Vec<Relaxation<(usize, usize)>>,
+    ) {
+        expand_cell(
+            curr,
+            relaxations,
+            self.height_map,
+            &self.cost_model,
+            self.connectivity,
+        );
+        relaxations.retain(|relaxation| self.cluster(relaxation.position) == cluster);
+    }
+
+    /// Dijkstra from `from` inside its cluster, until every one of `targets` is settled.
+    /// The expanded cells are added to `stats`.
+    fn cluster_tree(
+        &self,
+        from: (usize, usize),
+        targets: &[(usize, usize)],
+        stats: &mut SearchStats,
+        observer: &mut impl SearchObserver,
+    ) -> Result<SearchTree<(usize, usize)>, RoutingError> {
+        let cluster = self.cluster(from);
+        let mut remaining = targets.len();
+        grow_search_tree(
+            from,
+            |position| {
+                if targets.contains(&position) {
+                    remaining -= 1;
+                }
+                remaining == 0
+            },
+            |_| 0,
+            |curr, relaxations| self.expand_in_cluster(cluster, curr, relaxations),
+            true,
+            stats,
+            observer,
+        )
+    }
+
+    /// The cheapest path from `from` to `to` that stays inside their shared cluster.
+    /// The expanded cells are added to `stats`.
+    fn cluster_path(
+        &self,
+        from: (usize, usize),
+        to: (usize, usize),
+        stats: &mut SearchStats,
+        observer: &mut impl SearchObserver,
+    ) -> Result<Vec<(usize, usize)>, RoutingError> {
+        let cluster = self.cluster(from);
+        let result = best_first_search(
+            from,
+            |position| position == to,
+            |position| {
+                self.cost_model
+                    .lower_bound(self.height_map, position, to, self.connectivity)
+            },
+            |curr, relaxations| self.expand_in_cluster(cluster, curr, relaxations),
+            true,
+            stats,
+            observer,
+        )?;
+        // The abstract graph only has steps that were found by the same search, so this
+        // only fails if the cost model changed its mind.
+        let (path, _) = result.ok_or(RoutingError::NoFeasibleRoute {
+            start: from,
+            end: to,
+        })?;
+        Ok(path)
+    }
+
+    /// Routes from `start` to `end` over the abstract graph, then refines the result.
+    ///
+    /// Returns `RoutingError::NoFeasibleRoute` if the abstract graph has no way of reaching
+    /// `end`, which may happen even though an exact router would find one if the way
+    /// leaves a cluster and comes back without using an entrance.
+    pub fn route(
+        &self,
+        start: (usize, usize),
+        end: (usize, usize),
+    ) -> Result<HierarchicalRoute, RoutingError> {
+        self.route_observed(start, end, &mut NoObserver)
+    }
+
+    /// Like `route`, but reports the progress of all the searches it runs together to
+    /// `observer`, which may cancel them.
+    pub fn route_observed(
+        &self,
+        start: (usize, usize),
+        end: (usize, usize),
+        observer: &mut impl SearchObserver,
+    ) -> Result<HierarchicalRoute, RoutingError> {
+        check_bounds(self.height_map, &[start, end])?;
+        let mut stats = SearchStats::default();
+        // Connect `start` to its cluster's entrances, and its cluster's entrances to `end`.
+        let start_targets = self
+            .entrances
+            .get(&self.cluster(start))
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain((self.cluster(start) == self.cluster(end)).then_some(end))
+            .collect::<Vec<_>>();
+        let start_tree = self.cluster_tree(start, &start_targets, &mut stats, observer)?;
+        let start_edges = start_targets
+            .iter()
+            .filter_map(|&to| Some((to, start_tree.cost(to)?)))
+            .collect::<Vec<_>>();
+
+        let mut end_edges = HashMap::new();
+        for &from in self.entrances.get(&self.cluster(end)).into_iter().flatten() {
+            if let Some(cost) = self
+                .cluster_tree(from, &[end], &mut stats, observer)?
+                .cost(end)
+            {
+                end_edges.insert(from, cost);
+            }
+        }
+
+        let result = best_first_search(
+            start,
+            |position| position == end,
+            |position| {
+                self.cost_model
+                    .lower_bound(self.height_map, position, end, self.connectivity)
+            },
+            |curr, relaxations| {
+                let from = curr.position;
+                let graph_steps = self.edges.get(&from).into_iter().flatten();
+                let start_steps = if from == start { &start_edges[..] } else { &[] };
+                let end_step = end_edges.get(&from).map(|&cost| (end, cost));
+                let steps = graph_steps.chain(start_steps).copied().chain(end_step);
+                relaxations.extend(steps.map(|(position, step_cost)| Relaxation {
+                    position,
+                    from,
+                    step_cost,
+                }));
+            },
+            true,
+            &mut stats,
+            observer,
+        )?;
+        let Some((abstract_path, cost)) = result else {
+            return Err(RoutingError::NoFeasibleRoute { start, end });
+        };
+
+        let mut path = vec![start];
+        for step in abstract_path.windows(2) {
+            if self.cluster(step[0]) == self.cluster(step[1]) {
+                let refined = self.cluster_path(step[0], step[1], &mut stats, observer)?;
+                path.extend(refined.into_iter().skip(1));
+            } else {
+                path.push(step[1]);
+            }
+        }
+
+        Ok(HierarchicalRoute {
+            route: Route { path, cost },
+            lower_bound: self.cost_model.lower_bound(
+                self.height_map,
+                start,
+                end,
+                self.connectivity,
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cost_model::{AbsoluteClimb, FlatDistance, NoGo},
+        datatypes::Raster,
+        dijkstra,
+    };
+
+    const LAYOUT: ClusterLayout = ClusterLayout {
+        size: 16,
+        entrance_spacing: 4,
+    };
+
+    /// Rolling hills, so that routes have a reason to wind.
+    fn hilly_height_map(size: usize) -> HeightMap {
+        HeightMap::from_fn(size, size, |(x, y)| {
+            let (x, y) = (x as f64, y as f64);
+            ((x / 9.0).sin() * 12.0 + (y / 7.0).cos() * 12.0 + (x + y) / 5.0) as i32
+        })
+    }
+
+    #[test]
+    fn close_to_exact_routes() {
+        let height_map = hilly_height_map(96);
+        let cost_model = AbsoluteClimb::default();
+        let pairs = [
+            ((0, 0), (95, 95)),
+            ((3, 90), (88, 2)),
+            ((40, 41), (45, 43)),
+            ((10, 50), (90, 50)),
+        ];
+
+        for connectivity in [Connectivity::Four, Connectivity::Eight] {
+            let router =
+                HierarchicalRouter::new(&height_map, cost_model, connectivity, LAYOUT).unwrap();
+            for (start, end) in pairs {
+                let exact = dijkstra(start, end, &height_map, &cost_model, connectivity).unwrap();
+                let hierarchical = router.route(start, end).unwrap();
+                let route = &hierarchical.route;
+
+                assert_eq!(route.path.first(), Some(&start));
+                assert_eq!(route.path.last(), Some(&end));
+                let path_cost = route
+                    .path
+                    .windows(2)
+                    .map(|step| {
+                        assert!(connectivity
+                            .neighbor_offsets()
+                            .contains(&as_i32_offset(step[0], step[1])));
+                        cost_model.step_cost(&height_map, step[0], step[1]).unwrap()
+                    })
+                    .sum::<usize>();
+                assert_eq!(route.cost, path_cost);
+
+                assert!(hierarchical.lower_bound <= exact.cost);
+                assert!(route.cost >= exact.cost);
+                assert!(
+                    route.cost as f64 <= exact.cost as f64 * 1.1,
+                    "{} vs exact {} from {:?} to {:?}",
+                    route.cost,
+                    exact.cost,
+                    start,
+                    end
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn suboptimality_bound_holds_on_real_terrain() {
+        let height_map = crate::tests::load_height_map_256_256();
+        let cost_model = AbsoluteClimb::default();
+        let router =
+            HierarchicalRouter::new(&height_map, cost_model, Connectivity::Eight, LAYOUT).unwrap();
+
+        for (start, end) in [
+            ((0, 0), (255, 255)),
+            ((10, 240), (230, 20)),
+            ((128, 5), (120, 250)),
+        ] {
+            let exact =
+                dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight).unwrap();
+            let hierarchical = router.route(start, end).unwrap();
+            let bound = hierarchical
+                .suboptimality_bound()
+                .expect("AbsoluteClimb has a positive lower bound between distinct cells");
+            assert!(
+                hierarchical.route.cost as f64 <= bound * exact.cost as f64,
+                "{} vs exact {} with bound {}",
+                hierarchical.route.cost,
+                exact.cost,
+                bound
+            );
+            // Rough terrain winds more than the hills above, so allow a little more slack.
+            assert!(hierarchical.route.cost as f64 <= 1.15 * exact.cost as f64);
+        }
+    }
+
+    #[test]
+    fn suboptimality_is_unbounded_without_a_lower_bound() {
+        /// `AbsoluteClimb` with the default lower bound of 0.
+        struct NoLowerBound;
+        impl CostModel for NoLowerBound {
+            fn step_cost(
+                &self,
+                height_map: &HeightMap,
+                from: (usize, usize),
+                to: (usize, usize),
+            ) -> Option<usize> {
+                AbsoluteClimb::default().step_cost(height_map, from, to)
+            }
+        }
+
+        let height_map = hilly_height_map(40);
+        let router =
+            HierarchicalRouter::new(&height_map, NoLowerBound, Connectivity::Four, LAYOUT).unwrap();
+        let route = router.route((0, 0), (39, 39)).unwrap();
+        assert!(route.route.cost > 0);
+        assert_eq!(route.lower_bound, 0);
+        assert_eq!(route.suboptimality_bound(), None);
+    }
+
+    #[test]
+    fn rejects_empty_clusters() {
+        let height_map = hilly_height_map(20);
+        for layout in [
+            ClusterLayout {
+                size: 0,
+                entrance_spacing: 4,
+            },
+            ClusterLayout {
+                size: 16,
+                entrance_spacing: 0,
+            },
+        ] {
+            assert!(matches!(
+                HierarchicalRouter::new(&height_map, FlatDistance, Connectivity::Four, layout),
+                Err(RoutingError::InvalidSetting { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn finds_gaps_in_walls() {
+        let height_map = HeightMap::new(40, 40, vec![0; 40 * 40]);
+        let mut mask = Raster::from_fn(40, 40, |(x, _)| x == 20);
+        let walled_off = NoGo {
+            inner: AbsoluteClimb::default(),
+            mask: mask.clone(),
+        };
+        let router =
+            HierarchicalRouter::new(&height_map, &walled_off, Connectivity::Four, LAYOUT).unwrap();
+        assert_eq!(
+            router.route((0, 0), (39, 39)),
+            Err(RoutingError::NoFeasibleRoute {
+                start: (0, 0),
+                end: (39, 39)
+            })
+        );
+
+        mask[(20, 33)] = false;
+        let cost_model = NoGo {
+            inner: AbsoluteClimb::default(),
+            mask,
+        };
+        let router =
+            HierarchicalRouter::new(&height_map, &cost_model, Connectivity::Four, LAYOUT).unwrap();
+        let route = router.route((0, 0), (39, 0)).unwrap().route;
+        assert!(route.path.contains(&(20, 33)));
+        assert_eq!(
+            route.cost,
+            dijkstra(
+                (0, 0),
+                (39, 0),
+                &height_map,
+                &cost_model,
+                Connectivity::Four
+            )
+            .unwrap()
+            .cost
+        );
+    }
+
+    #[test]
+    fn routes_within_one_cluster() {
+        let height_map = hilly_height_map(20);
+        let router = HierarchicalRouter::new(
+            &height_map,
+            AbsoluteClimb::default(),
+            Connectivity::Eight,
+            LAYOUT,
+        )
+        .unwrap();
+
+        let route = router.route((2, 2), (2, 2)).unwrap();
+        assert_eq!(route.route.path, vec![(2, 2)]);
+        assert_eq!(route.suboptimality_bound(), Some(1.0));
+
+        let route = router.route((1, 1), (8, 7)).unwrap().route;
+        assert_eq!(route.path.first(), Some(&(1, 1)));
+        assert_eq!(route.path.last(), Some(&(8, 7)));
+    }
+
+    fn as_i32_offset(from: (usize, usize), to: (usize, usize)) -> (i32, i32) {
+        (to.0 as i32 - from.0 as i32, to.1 as i32 - from.1 as i32)
+    }
+}
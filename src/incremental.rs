@@ -0,0 +1,509 @@
+// Incremental re-routing: repairing a route after parts of the height map are edited,
+// instead of searching again from scratch.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+};
+
+use crate::{
+    check_bounds,
+    cost_model::CostModel,
+    datatypes::{HeightMap, Raster},
+    progress::{self, NoObserver, Progress, SearchObserver},
+    Connectivity, Route, RoutingError, SearchStats,
+};
+
+/// The cost of a cell that hasn't been reached.
+const UNREACHED: Cost = (usize::MAX, usize::MAX);
+
+/// Keeps the search state for a route from `start` to `end`, so that the route can be
+/// repaired with Lifelong Planning A* (LPA*) when the height map changes.
+///
+/// Only the cells whose cheapest cost is affected by an edit are expanded again, so
+/// edits away from the route, or behind the search frontier, cost next to nothing, and
+/// the route through unchanged areas comes back identical.
+///
+/// Step costs must only depend on the heights of the two cells of the step (as with
+/// `FlatDistance`, `AbsoluteClimb` and `MaxGrade`), since only steps into and out of edited
+/// cells are costed again. `Connectivity::AnyAngle` is treated like `Connectivity::Eight`.
+pub struct IncrementalRouter<M> {
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: HeightMap,
+    cost_model: M,
+    connectivity: Connectivity,
+    /// The cost of the cheapest path to each cell, as of when it was last expanded
+    /// (`g` in LPA*).
+    cost: Raster<Cost>,
+    /// The cost of each cell through the best of its neighbours' `cost` (`rhs` in LPA*).
+    /// Cells where this differs from `cost` are waiting to be expanded.
+    lookahead: Raster<Cost>,
+    /// The neighbour that `lookahead` comes through, to walk the route back from `end`.
+    parent: Raster<Option<(usize, usize)>>,
+    /// The cells waiting to be expanded, by priority. Entries whose priority is out of
+    /// date are skipped when popped.
+    frontier: BinaryHeap<Reverse<(Priority, (usize, usize))>>,
+}
+
+/// The cost of a path, then its number of steps. Counting steps breaks ties between
+/// equally cheap paths, so that zero cost steps can't keep a stale cost going round a loop
+/// of cells (LPA* needs every step to cost something).
+type Cost = (usize, usize);
+
+/// The estimated total cost through a cell, then its cost so far to break ties.
+type Priority = (usize, Cost);
+
+impl<M: CostModel> IncrementalRouter<M> {
+    /// Sets up the search from `start` to `end` over `height_map`. Nothing is searched until
+    /// the first call to `route`.
+    pub fn new(
+        start: (usize, usize),
+        end: (usize, usize),
+        height_map: HeightMap,
+        cost_model: M,
+        connectivity: Connectivity,
+    ) -> Result<Self, RoutingError> {
+        check_bounds(&height_map, &[start, end])?;
+        let connectivity = match connectivity {
+            Connectivity::AnyAngle => Connectivity::Eight,
+            connectivity => connectivity,
+        };
+
+        let mut router = Self {
+            start,
+            end,
+            cost: height_map.raster(UNREACHED),
+            lookahead: height_map.raster(UNREACHED),
+            parent: height_map.raster(None),
+            height_map,
+            cost_model,
+            connectivity,
+            frontier: BinaryHeap::new(),
+        };
+        router.lookahead[start] = (0, 0);
+        router
+            .frontier
+            .push(Reverse((router.priority(start), start)));
+        Ok(router)
+    }
+
+    /// Replaces the height map with `height_map`, which may only differ from the current
+    /// one at the `changed` cells, and marks the cells whose cost may have changed.
+    ///
+    /// The route is repaired by the next call to `route`. Returns
+    /// `RoutingError::OutOfBounds` if a changed cell is outside of the height map, and
+    /// `RoutingError::InvalidSetting` if the new height map has a different size, leaving
+    /// the router as it was.
+    pub fn update(
+        &mut self,
+        height_map: HeightMap,
+        changed: &HashSet<(usize, usize)>,
+    ) -> Result<(), RoutingError> {
+        check_bounds(
+            &self.height_map,
+            &changed.iter().copied().collect::<Vec<_>>(),
+        )?;
+        if height_map.size() != self.height_map.size() {
+            return Err(RoutingError::InvalidSetting {
+                name: "height map",
+                reason: "can't change size",
+            });
+        }
+        self.height_map = height_map;
+
+        // Steps into and out of a changed cell have new costs, which affects the cell
+        // itself and every neighbour it leads to.
+        let mut affected = HashSet::new();
+        for &cell in changed {
+            affected.insert(cell);
+            affected.extend(self.neighbors(cell));
+        }
+        for cell in affected {
+            self.update_cell(cell)?;
+        }
+
+        // The heuristic depends on the terrain too, so every waiting cell needs a
+        // new priority.
+        let waiting = self
+            .frontier
+            .drain()
+            .map(|Reverse((_, cell))| cell)
+            .collect::<HashSet<_>>();
+        for cell in waiting {
+            if self.cost[cell] != self.lookahead[cell] {
+                self.frontier.push(Reverse((self.priority(cell), cell)));
+            }
+        }
+        Ok(())
+    }
+
+    /// The cheapest route from `start` to `end` over the current height map, and how many
+    /// cells had to be expanded to find or repair it since the previous call.
+    ///
+    /// Returns `RoutingError::NoFeasibleRoute` if the cost model forbids every way of
+    /// reaching `end`.
+    pub fn route(&mut self) -> Result<(Route, SearchStats), RoutingError> {
+        self.route_observed(&mut NoObserver)
+    }
+
+    /// Like `route`, but reports its progress to `observer`, which may cancel the search.
+    ///
+    /// Cancelling keeps everything expanded so far, so the next call to `route` carries on
+    /// from there.
+    pub fn route_observed(
+        &mut self,
+        observer: &mut impl SearchObserver,
+    ) -> Result<(Route, SearchStats), RoutingError> {
+        let mut stats = SearchStats::default();
+        self.expand_until_settled(&mut stats, observer)?;
+
+        let (start, end) = (self.start, self.end);
+        let (cost, _) = self.cost[end];
+        if self.cost[end] == UNREACHED {
+            return Err(RoutingError::NoFeasibleRoute { start, end });
+        }
+
+        // Walk back from the end through the neighbours that the cheapest costs came from.
+        let mut reverse_path = vec![end];
+        let mut curr = end;
+        while curr != start {
+            curr = self.parent[curr].expect("every reached cell comes from a neighbour");
+            reverse_path.push(curr);
+        }
+        reverse_path.reverse();
+
+        Ok((
+            Route {
+                path: reverse_path,
+                cost,
+            },
+            stats,
+        ))
+    }
+
+    /// Expands cells until the cost of `end` is final.
+    fn expand_until_settled(
+        &mut self,
+        stats: &mut SearchStats,
+        observer: &mut impl SearchObserver,
+    ) -> Result<(), RoutingError> {
+        let end = self.end;
+        while let Some(&Reverse((priority, cell))) = self.frontier.peek() {
+            let (cost, lookahead) = (self.cost[cell], self.lookahead[cell]);
+            // The cell has been expanded or pushed again since this entry was pushed.
+            if cost == lookahead || priority != self.priority(cell) {
+                self.frontier.pop();
+                continue;
+            }
+            if priority >= self.priority(end) && self.cost[end] == self.lookahead[end] {
+                break;
+            }
+            self.frontier.pop();
+            stats.expanded += 1;
+
+            if cost > lookahead {
+                // A cheaper way of reaching the cell was found.
+                self.cost[cell] = lookahead;
+            } else {
+                // The cell got more expensive. Forget its cost and work it out again from
+                // its neighbours.
+                self.cost[cell] = UNREACHED;
+                self.update_cell(cell)?;
+            }
+            for neighbor in self.neighbors(cell).collect::<Vec<_>>() {
+                self.update_cell(neighbor)?;
+            }
+
+            // Only report once the cell is done with, so a cancelled search can resume.
+            let (best_cost, (cost, _)) = priority;
+            progress::report(
+                observer,
+                Progress {
+                    expanded: stats.expanded,
+                    frontier: self.frontier.len(),
+                    cost,
+                    best_cost,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Works out the lookahead cost of `cell` again, and queues it for expansion if that
+    /// differs from its cost.
+    fn update_cell(&mut self, cell: (usize, usize)) -> Result<(), RoutingError> {
+        if cell != self.start {
+            let (mut lookahead, mut parent) = (UNREACHED, None);
+            for from in self.neighbors(cell) {
+                let from_cost = self.cost[from];
+                let step_cost = self.cost_model.step_cost(&self.height_map, from, cell);
+                if let (true, Some(step_cost)) = (from_cost != UNREACHED, step_cost) {
+                    let through = (
+                        from_cost
+                            .0
+                            .checked_add(step_cost)
+                            .ok_or(RoutingError::CostOverflow)?,
+                        from_cost.1 + 1,
+                    );
+                    // Keep the current parent on a tie, so the route only moves where it
+                    // has to.
+                    if through < lookahead
+                        || (through == lookahead && Some(from) == self.parent[cell])
+                    {
+                        (lookahead, parent) = (through, Some(from));
+                    }
+                }
+            }
+            self.lookahead[cell] = lookahead;
+            self.parent[cell] = parent;
+        }
+        if self.cost[cell] != self.lookahead[cell] {
+            self.frontier.push(Reverse((self.priority(cell), cell)));
+        }
+        Ok(())
+    }
+
+    fn priority(&self, cell: (usize, usize)) -> Priority {
+        let cost = self.cost[cell].min(self.lookahead[cell]);
+        let estimate =
+            self.cost_model
+                .lower_bound(&self.height_map, cell, self.end, self.connectivity);
+        (cost.0.saturating_add(estimate), cost)
+    }
+
+    fn neighbors(&self, cell: (usize, usize)) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.height_map.neighbors(cell, self.connectivity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use super::*;
+    use crate::{
+        a_star,
+        cost_model::{AbsoluteClimb, HeightChange},
+        dijkstra,
+    };
+
+    fn rolling_hills(size: usize) -> HeightMap {
+        HeightMap::from_fn(size, size, |(x, y)| {
+            ((x as f64 / 5.0).sin() * 4.0 + (y as f64 / 7.0).cos() * 3.0) as i32
+        })
+    }
+
+    /// Raises every cell in `cells` by `height`, returning the new map and the changed cells.
+    fn raise(
+        height_map: &HeightMap,
+        cells: impl Iterator<Item = (usize, usize)>,
+        height: i32,
+    ) -> (HeightMap, HashSet<(usize, usize)>) {
+        let mut height_map = height_map.clone();
+        let changed = cells.collect::<HashSet<_>>();
+        for &cell in &changed {
+            height_map[cell] += height;
+        }
+        (height_map, changed)
+    }
+
+    #[test]
+    fn repairs_routes_after_edits() {
+        let height_map = rolling_hills(40);
+        let (start, end) = ((2, 20), (37, 20));
+        let cost_model = AbsoluteClimb::default();
+
+        let mut router = IncrementalRouter::new(
+            start,
+            end,
+            height_map.clone(),
+            cost_model,
+            Connectivity::Eight,
+        )
+        .unwrap();
+        let (route, _) = router.route().unwrap();
+        assert_eq!(
+            route.cost,
+            dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight)
+                .unwrap()
+                .cost
+        );
+
+        // A ridge across the middle of the route, with a gap at one end.
+        let (ridged, changed) = raise(&height_map, (0..34).map(|y| (20, y)), 30);
+        router.update(ridged.clone(), &changed).unwrap();
+        let (repaired, repair_stats) = router.route().unwrap();
+        let (expected, fresh_stats) = a_star(
+            start,
+            end,
+            &ridged,
+            &cost_model,
+            Connectivity::Eight,
+            |from, to, height_map| {
+                cost_model.lower_bound(height_map, from, to, Connectivity::Eight)
+            },
+        )
+        .unwrap();
+        assert_eq!(repaired.cost, expected.cost);
+        let path_cost = repaired
+            .path
+            .windows(2)
+            .map(|step| cost_model.step_cost(&ridged, step[0], step[1]).unwrap())
+            .sum::<usize>();
+        assert_eq!(path_cost, repaired.cost);
+        assert!(repaired.path.iter().all(|cell| !changed.contains(cell)));
+        assert!(repair_stats.expanded < fresh_stats.expanded);
+
+        // Taking the ridge away again brings back the original cost.
+        router.update(height_map.clone(), &changed).unwrap();
+        let (restored, _) = router.route().unwrap();
+        assert_eq!(restored.cost, route.cost);
+    }
+
+    #[test]
+    fn unchanged_areas_keep_their_route() {
+        let height_map = rolling_hills(40);
+        let (start, end) = ((2, 5), (37, 8));
+
+        let mut router = IncrementalRouter::new(
+            start,
+            end,
+            height_map.clone(),
+            AbsoluteClimb::default(),
+            Connectivity::Eight,
+        )
+        .unwrap();
+        let (route, _) = router.route().unwrap();
+
+        // A hill and a pit in the far corner, well away from the route.
+        for height in [20, -20] {
+            let (edited, changed) = raise(
+                &height_map,
+                (30..35).flat_map(|x| (30..35).map(move |y| (x, y))),
+                height,
+            );
+            router.update(edited, &changed).unwrap();
+            let (rerouted, stats) = router.route().unwrap();
+            assert_eq!(rerouted, route);
+            assert_eq!(stats.expanded, 0);
+        }
+    }
+
+    #[test]
+    fn free_steps_give_simple_routes() {
+        // On flat ground every step is free, so most neighbours tie as the way back.
+        let height_map = HeightMap::new(30, 30, vec![0; 30 * 30]);
+        let (start, end) = ((3, 4), (26, 21));
+        let mut router = IncrementalRouter::new(
+            start,
+            end,
+            height_map.clone(),
+            HeightChange,
+            Connectivity::Eight,
+        )
+        .unwrap();
+
+        let assert_simple = |route: &Route, height_map: &HeightMap| {
+            assert_eq!(route.path.first(), Some(&start));
+            assert_eq!(route.path.last(), Some(&end));
+            let cells = route.path.iter().collect::<HashSet<_>>();
+            assert_eq!(cells.len(), route.path.len(), "{:?} has a loop", route.path);
+            let path_cost = route
+                .path
+                .windows(2)
+                .map(|step| {
+                    assert!(
+                        step[0].0.abs_diff(step[1].0) <= 1 && step[0].1.abs_diff(step[1].1) <= 1
+                    );
+                    HeightChange
+                        .step_cost(height_map, step[0], step[1])
+                        .unwrap()
+                })
+                .sum::<usize>();
+            assert_eq!(path_cost, route.cost);
+        };
+        let (route, _) = router.route().unwrap();
+        assert_eq!(route.cost, 0);
+        assert_simple(&route, &height_map);
+
+        // Wall off most of the way with a ridge, then a pit, that the route can go round
+        // for free.
+        for height in [5, -5] {
+            let (edited, changed) = raise(&height_map, (0..25).map(|y| (15, y)), height);
+            router.update(edited.clone(), &changed).unwrap();
+            let (route, _) = router.route().unwrap();
+            assert_eq!(route.cost, 0);
+            assert_simple(&route, &edited);
+            router.update(height_map.clone(), &changed).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_bad_edits() {
+        let height_map = rolling_hills(20);
+        let mut router = IncrementalRouter::new(
+            (0, 0),
+            (19, 19),
+            height_map.clone(),
+            AbsoluteClimb::default(),
+            Connectivity::Four,
+        )
+        .unwrap();
+        let (route, _) = router.route().unwrap();
+
+        assert_eq!(
+            router.update(height_map.clone(), &HashSet::from([(3, 20)])),
+            Err(RoutingError::OutOfBounds {
+                point: (3, 20),
+                size: (20, 20)
+            })
+        );
+        assert!(matches!(
+            router.update(rolling_hills(21), &HashSet::new()),
+            Err(RoutingError::InvalidSetting { .. })
+        ));
+        assert_eq!(router.route().unwrap().0, route);
+    }
+
+    #[test]
+    fn cancelled_searches_resume() {
+        let height_map = rolling_hills(40);
+        let (start, end) = ((2, 20), (37, 20));
+        let cost_model = AbsoluteClimb::default();
+        let mut router = IncrementalRouter::new(
+            start,
+            end,
+            height_map.clone(),
+            cost_model,
+            Connectivity::Eight,
+        )
+        .unwrap();
+
+        struct Impatient;
+        impl SearchObserver for Impatient {
+            fn progress(&mut self, _progress: &Progress) -> ControlFlow<()> {
+                ControlFlow::Break(())
+            }
+
+            fn interval(&self) -> usize {
+                50
+            }
+        }
+        assert!(matches!(
+            router.route_observed(&mut Impatient),
+            Err(RoutingError::Cancelled {
+                progress: Progress { expanded: 50, .. }
+            })
+        ));
+
+        let (route, stats) = router.route().unwrap();
+        assert_eq!(
+            route.cost,
+            dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight)
+                .unwrap()
+                .cost
+        );
+        assert!(stats.expanded > 0);
+    }
+}
@@ -1,126 +1,727 @@
-#![feature(generic_const_exprs)]
+mod alternatives;
+mod batch;
+mod cost_model;
+mod datatypes;
+mod earthwork;
+mod heading;
+mod hierarchical;
+mod incremental;
 mod magica_voxel;
+mod pareto;
+mod platform;
+mod profile;
+mod progress;
+mod shortest_path_tree;
+mod simplify;
+mod structures;
+mod trace;
 mod voxel;
 mod voxel_model;
+mod voxel_routing;
 
 use std::{
     cmp::Ordering,
-    collections::{BinaryHeap, HashMap},
+    collections::{BinaryHeap, HashMap, HashSet},
+    error::Error,
+    fmt,
+    hash::Hash,
+    iter,
 };
+
+use cost_model::{distance, CostModel};
+use datatypes::HeightMap;
+use progress::{NoObserver, Progress, SearchObserver};
 #[macro_use]
 extern crate static_assertions;
 
+/// An entry in the search frontier.
+/// `P` is a position in the search space, which need not be just a cell.
 #[derive(Copy, Clone, Eq)]
-struct HeapState {
+struct HeapState<P = (usize, usize)> {
     cost: usize,
-    position: (i32, i32),
+    // `cost` plus the heuristic estimate of the remaining cost to the goal.
+    // This is what the frontier is ordered by.
+    priority: usize,
+    position: P,
 }
-impl HeapState {
-    fn new(cost: usize, position: (i32, i32)) -> Self {
-        Self { cost, position }
+impl<P> HeapState<P> {
+    fn new(cost: usize, priority: usize, position: P) -> Self {
+        Self {
+            cost,
+            priority,
+            position,
+        }
     }
 }
-impl PartialEq for HeapState {
+impl<P> PartialEq for HeapState<P> {
     fn eq(&self, other: &Self) -> bool {
-        self.cost == other.cost
+        self.priority == other.priority && self.cost == other.cost
     }
 }
 
 // The priority queue depends on `Ord`.
 // Explicitly implement the trait so the queue becomes a min-heap
 // instead of a max-heap.
-impl Ord for HeapState {
+impl<P: Eq> Ord for HeapState<P> {
     fn cmp(&self, other: &Self) -> Ordering {
-        // Notice that the we flip the ordering on costs.
-        // In case of a tie we compare positions - this step is necessary
-        // to make implementations of `PartialEq` and `Ord` consistent.
-        other.cost.cmp(&self.cost)
+        // Notice that the we flip the ordering on priorities.
+        // In case of a tie we prefer the state with the larger cost (i.e. the one
+        // closest to the goal according to the heuristic), which keeps A* from
+        // fanning out across plateaus of equal priority.
+        other
+            .priority
+            .cmp(&self.priority)
+            .then_with(|| self.cost.cmp(&other.cost))
     }
 }
 
 // `PartialOrd` needs to be implemented as well.
-impl PartialOrd for HeapState {
+impl<P: Eq> PartialOrd for HeapState<P> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
+/// An optimal path between two cells, along with what it costs to travel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Route {
+    /// Every cell on the path, from the start to the end inclusive.
+    path: Vec<(usize, usize)>,
+    /// Sum of the cost model's step costs over every step of `path`.
+    cost: usize,
+}
+
+/// Why a route could not be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RoutingError {
+    /// The height map has no cells.
+    EmptyHeightMap,
+    /// A start or end point lies outside of the height map.
+    OutOfBounds {
+        point: (usize, usize),
+        size: (usize, usize),
+    },
+    /// Every path from the start to the end takes a step that the cost model forbids.
+    NoFeasibleRoute {
+        start: (usize, usize),
+        end: (usize, usize),
+    },
+    /// A start or end voxel lies outside of the block being routed through.
+    VoxelOutOfBounds {
+        voxel: (usize, usize, usize),
+        size: (usize, usize, usize),
+    },
+    /// Every path from the start voxel to the end voxel breaks the grade or clearance limits.
+    NoFeasibleVoxelRoute {
+        start: (usize, usize, usize),
+        end: (usize, usize, usize),
+    },
+    /// The cost of a path no longer fits in a `usize`.
+    CostOverflow,
+    /// A `SearchObserver` stopped the search, after it got as far as `progress`.
+    Cancelled { progress: Progress },
+    /// A setting passed to a router is outside of its valid range.
+    InvalidSetting {
+        name: &'static str,
+        reason: &'static str,
+    },
+    /// Routing between two consecutive waypoints failed.
+    /// Leg `i` runs from waypoint `i` to waypoint `i + 1`, counting the start as waypoint 0.
+    LegFailed {
+        leg: usize,
+        error: Box<RoutingError>,
+    },
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingError::EmptyHeightMap => write!(f, "the height map is empty"),
+            RoutingError::OutOfBounds { point, size } => write!(
+                f,
+                "{:?} is outside of the {}x{} height map",
+                point, size.0, size.1
+            ),
+            RoutingError::NoFeasibleRoute { start, end } => {
+                write!(f, "no feasible route from {:?} to {:?}", start, end)
+            }
+            RoutingError::VoxelOutOfBounds { voxel, size } => write!(
+                f,
+                "{:?} is outside of the {}x{}x{} block",
+                voxel, size.0, size.1, size.2
+            ),
+            RoutingError::NoFeasibleVoxelRoute { start, end } => {
+                write!(f, "no feasible voxel route from {:?} to {:?}", start, end)
+            }
+            RoutingError::CostOverflow => write!(f, "the route cost overflowed"),
+            RoutingError::Cancelled { progress } => write!(
+                f,
+                "the search was cancelled after expanding {} positions",
+                progress.expanded
+            ),
+            RoutingError::InvalidSetting { name, reason } => {
+                write!(f, "invalid {}: {}", name, reason)
+            }
+            RoutingError::LegFailed { leg, error } => write!(f, "leg {} failed: {}", leg, error),
+        }
+    }
+}
+
+impl Error for RoutingError {}
+
+/// Checks that the height map is not empty and that every point lies inside of it.
+///
+/// Returns the size of the height map.
+fn check_bounds(
+    height_map: &HeightMap,
+    points: &[(usize, usize)],
+) -> Result<(usize, usize), RoutingError> {
+    if height_map.is_empty() {
+        return Err(RoutingError::EmptyHeightMap);
+    }
+    let size = height_map.size();
+    match points.iter().find(|&&point| !height_map.contains(point)) {
+        Some(&point) => Err(RoutingError::OutOfBounds { point, size }),
+        None => Ok(size),
+    }
+}
+
+/// Which moves the router may make between cells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connectivity {
+    /// Only to the 4 orthogonal neighbours, which gives staircase paths.
+    Four,
+    /// To all 8 neighbours, including the diagonals.
+    Eight,
+    /// Like `Eight`, but a cell may also be reached in a straight line from any cell
+    /// on the path that it has line of sight to (Theta*).
+    /// Consecutive cells of the returned path are then joined by straight segments
+    /// of any length.
+    AnyAngle,
+}
+
+impl Connectivity {
+    /// Whether searches over cells may reopen a settled cell, see `grow_search_tree`.
+    ///
+    /// Theta* can't: its shortcuts back to the parent of a reopened cell would leave the
+    /// costs already recorded further along out of date.
+    fn can_reopen(self) -> bool {
+        self != Connectivity::AnyAngle
+    }
+
+    fn neighbor_offsets(self) -> &'static [(i32, i32)] {
+        match self {
+            Connectivity::Four => &[(-1, 0), (1, 0), (0, 1), (0, -1)],
+            Connectivity::Eight | Connectivity::AnyAngle => &[
+                (-1, 0),
+                (1, 0),
+                (0, 1),
+                (0, -1),
+                (-1, -1),
+                (-1, 1),
+                (1, -1),
+                (1, 1),
+            ],
+        }
+    }
+}
+
+/// The cells crossed by a straight line from `from` to `to`, both ends included.
+fn line_cells(from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+    // Bresenham's line algorithm.
+    let (mut x, mut y) = (from.0 as i64, from.1 as i64);
+    let (x_end, y_end) = (to.0 as i64, to.1 as i64);
+    let dx = (x_end - x).abs();
+    let dy = -(y_end - y).abs();
+    let step_x = if x < x_end { 1 } else { -1 };
+    let step_y = if y < y_end { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut cells = vec![from];
+    while (x, y) != (x_end, y_end) {
+        let doubled_error = 2 * error;
+        if doubled_error >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled_error <= dx {
+            error += dx;
+            y += step_y;
+        }
+        cells.push((x as usize, y as usize));
+    }
+    cells
+}
+
+/// Every cell `path` runs through, including the cells that any-angle segments cross.
+fn route_cells(path: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut cells = path.first().into_iter().copied().collect::<Vec<_>>();
+    for segment in path.windows(2) {
+        cells.extend(line_cells(segment[0], segment[1]).into_iter().skip(1));
+    }
+    cells
+}
+
+/// Whether track can be laid in a straight line from `from` to `to`.
+///
+/// That is the case when no terrain along the line rises above the straight line between
+/// the heights at both ends, and the cost model allows every step between the crossed cells.
+fn line_of_sight(
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    from: (usize, usize),
+    to: (usize, usize),
+) -> bool {
+    let cells = line_cells(from, to);
+    let from_height = height_map[from] as f64;
+    let to_height = height_map[to] as f64;
+    let length = distance(from, to);
+
+    cells.iter().all(|&cell| {
+        let along = distance(from, cell) / length;
+        height_map[cell] as f64 <= from_height + along * (to_height - from_height)
+    }) && cells
+        .windows(2)
+        .all(|step| cost_model.step_cost(height_map, step[0], step[1]).is_some())
+}
+
+/// Bookkeeping about how much work a search did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SearchStats {
+    /// Number of cells popped off the frontier and expanded.
+    expanded: usize,
+}
+
 fn dijkstra(
     start: (usize, usize),
     end: (usize, usize),
-    height_map: &Vec<Vec<i32>>,
-) -> Vec<(usize, usize)> {
-    let start = (start.0 as i32, start.1 as i32);
-    let end = (end.0 as i32, end.1 as i32);
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+) -> Result<Route, RoutingError> {
+    dijkstra_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        &mut NoObserver,
+    )
+}
+
+/// Like `dijkstra`, but reports its progress to `observer`, which may cancel the search.
+fn dijkstra_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    observer: &mut impl SearchObserver,
+) -> Result<Route, RoutingError> {
+    // Dijkstra is just A* that knows nothing about the remaining distance.
+    a_star_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        |_, _, _| 0,
+        observer,
+    )
+    .map(|(route, _)| route)
+}
+
+/// Routes from `start` to `end` through every point of `via`, in order.
+///
+/// Each leg is routed on its own with A*, and the legs are stitched into one route.
+/// If a leg can't be routed, the error is wrapped in `RoutingError::LegFailed`.
+fn route_via(
+    start: (usize, usize),
+    via: &[(usize, usize)],
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+) -> Result<Route, RoutingError> {
+    route_via_observed(
+        start,
+        via,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        &mut NoObserver,
+    )
+}
 
-    assert!(!height_map.is_empty());
-    assert!(!height_map[0].is_empty());
+/// Like `route_via`, but reports the progress of all the legs together to `observer`,
+/// which may cancel them. Cancelling isn't a failed leg, so it isn't wrapped in
+/// `RoutingError::LegFailed`.
+fn route_via_observed(
+    start: (usize, usize),
+    via: &[(usize, usize)],
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    observer: &mut impl SearchObserver,
+) -> Result<Route, RoutingError> {
+    let mut stats = SearchStats::default();
+    let waypoints = iter::once(start)
+        .chain(via.iter().copied())
+        .chain(iter::once(end))
+        .collect::<Vec<_>>();
+
+    let mut route = Route {
+        path: vec![start],
+        cost: 0,
+    };
+    for (leg, pair) in waypoints.windows(2).enumerate() {
+        let leg_route = a_star_with_stats(
+            pair[0],
+            pair[1],
+            height_map,
+            cost_model,
+            connectivity,
+            &mut stats,
+            observer,
+        )
+        .map_err(|error| match error {
+            RoutingError::Cancelled { .. } => error,
+            error => RoutingError::LegFailed {
+                leg,
+                error: Box::new(error),
+            },
+        })?;
 
-    let x_len = height_map.len() as i32;
-    let y_len = height_map[0].len() as i32;
-    // TODO: Use an actual 2d array instead of vec of vecs.
-    // TODO: Handle the terrible casting issue between usize and i32
-    //       - this is only a problem due to finding the neighbors in a non-infinite height map.
+        // Every leg starts where the previous one ended.
+        route.path.extend(leg_route.path.into_iter().skip(1));
+        route.cost = route
+            .cost
+            .checked_add(leg_route.cost)
+            .ok_or(RoutingError::CostOverflow)?;
+    }
+    Ok(route)
+}
+
+/// A position that `best_first_search` is expanding, along with the position it was
+/// reached from.
+#[derive(Clone, Copy)]
+struct Expansion<P> {
+    position: P,
+    parent: P,
+}
 
-    // Maps a point to the point that it came from
-    let mut visited = HashMap::new();
+/// A way of reaching `position` that `best_first_search` should consider:
+/// coming straight from `from`, which must already have been reached, at an extra
+/// cost of `step_cost`.
+struct Relaxation<P> {
+    position: P,
+    from: P,
+    step_cost: usize,
+}
+
+/// Everything a search found out about the positions it reached.
+struct SearchTree<P> {
+    start: P,
+    // Maps a position to the cheapest known cost of reaching it, and the position that it came from.
+    visited: HashMap<P, (usize, P)>,
+    // Positions that have been expanded. When reopening is allowed, a position may be
+    // expanded again at a lower cost.
+    settled: HashSet<P>,
+    // The positions in the order they were expanded, including every re-expansion.
+    expanded: Vec<P>,
+}
+
+impl<P: Copy + Eq + Hash> SearchTree<P> {
+    /// The cost of the cheapest path to `position`, if it has been settled.
+    fn cost(&self, position: P) -> Option<usize> {
+        if !self.settled.contains(&position) {
+            return None;
+        }
+        Some(self.visited[&position].0)
+    }
+
+    /// The positions from the start to `position` inclusive, if it has been settled.
+    fn path(&self, position: P) -> Option<Vec<P>> {
+        if !self.settled.contains(&position) {
+            return None;
+        }
+        // Backtrack via the visited map to get the path from the position to start - then reverse it.
+        let mut reverse_path = Vec::new();
+
+        let mut curr = position;
+        while curr != self.start {
+            reverse_path.push(curr);
+            curr = self.visited[&curr].1;
+        }
+        reverse_path.push(self.start);
+
+        reverse_path.reverse();
+        Some(reverse_path)
+    }
+}
+
+/// The shared core of the routers: best-first search from `start`, until `stop` returns
+/// true for a settled position or there is nothing left to expand.
+///
+/// `expand` is called once for every settled position, and pushes the ways of reaching
+/// other positions from it. It normally relaxes the neighbours of `Expansion::position`,
+/// but may also propose coming from `Expansion::parent` (which is what Theta* does).
+/// `heuristic` must never overestimate the remaining cost to a goal.
+///
+/// A position may be pushed onto the frontier several times: whenever a cheaper way of
+/// reaching it turns up, its best known cost and parent are overwritten and it is
+/// pushed again. Stale entries are skipped when popped. With a consistent heuristic each
+/// position is only expanded once, at its final cost. An admissible but inconsistent
+/// heuristic can settle a position too early. If `reopen` is true, the position is
+/// expanded again when the cheaper way turns up, so the goal is still reached at its
+/// optimal cost. Otherwise settled positions are final, which searches whose steps can
+/// skip over positions (like Theta*) need to keep their recorded costs consistent.
+///
+/// `observer` hears about the progress of the search every so often, and may cancel it
+/// with `RoutingError::Cancelled`.
+fn grow_search_tree<P: Copy + Eq + Hash>(
+    start: P,
+    mut stop: impl FnMut(P) -> bool,
+    heuristic: impl Fn(P) -> usize,
+    mut expand: impl FnMut(Expansion<P>, &mut Vec<Relaxation<P>>),
+    reopen: bool,
+    stats: &mut SearchStats,
+    observer: &mut impl SearchObserver,
+) -> Result<SearchTree<P>, RoutingError> {
+    let mut tree = SearchTree {
+        start,
+        visited: HashMap::new(),
+        settled: HashSet::new(),
+        expanded: Vec::new(),
+    };
+    let SearchTree {
+        visited,
+        settled,
+        expanded,
+        ..
+    } = &mut tree;
 
     let mut frontier = BinaryHeap::new();
+    let mut relaxations = Vec::new();
 
-    frontier.push(HeapState::new(0, start));
+    frontier.push(HeapState::new(0, heuristic(start), start));
 
-    assert!(visited.insert(start, start).is_none());
+    assert!(visited.insert(start, (0, start)).is_none());
 
     while let Some(curr) = frontier.pop() {
-        if curr.position == end {
+        // Skip entries superseded by a cheaper route, and settled positions unless reopening.
+        if curr.cost > visited[&curr.position].0 || (!settled.insert(curr.position) && !reopen) {
+            continue;
+        }
+        stats.expanded += 1;
+        expanded.push(curr.position);
+        if stop(curr.position) {
             break;
         }
-        let neighbors = [
-            (curr.position.0 - 1, curr.position.1),
-            (curr.position.0 + 1, curr.position.1),
-            (curr.position.0, curr.position.1 + 1),
-            (curr.position.0, curr.position.1 - 1),
-        ];
-
-        for neighbor in neighbors.into_iter().filter_map(|(x, y)| {
-            if x >= 0 && y >= 0 && x < x_len && y < y_len {
-                Some((x, y))
-            } else {
-                None
-            }
-        }) {
-            if visited.contains_key(&neighbor) {
+        progress::report(
+            observer,
+            Progress {
+                expanded: stats.expanded,
+                frontier: frontier.len(),
+                cost: curr.cost,
+                best_cost: curr.priority,
+            },
+        )?;
+        expand(
+            Expansion {
+                position: curr.position,
+                parent: visited[&curr.position].1,
+            },
+            &mut relaxations,
+        );
+
+        for Relaxation {
+            position,
+            from,
+            step_cost,
+        } in relaxations.drain(..)
+        {
+            let cost = visited[&from]
+                .0
+                .checked_add(step_cost)
+                .ok_or(RoutingError::CostOverflow)?;
+            if (!reopen && settled.contains(&position))
+                || visited
+                    .get(&position)
+                    .is_some_and(|&(best_cost, _)| best_cost <= cost)
+            {
                 continue;
             }
-            let neighbor_cost = curr.cost
-                + 1
-                + (height_map[neighbor.0 as usize][neighbor.1 as usize]
-                    - height_map[curr.position.0 as usize][curr.position.1 as usize])
-                    .checked_abs()
-                    .unwrap() as usize;
-
-            frontier.push(HeapState::new(neighbor_cost, neighbor));
-            visited.insert(neighbor, curr.position);
+            frontier.push(HeapState::new(
+                cost,
+                cost.saturating_add(heuristic(position)),
+                position,
+            ));
+            visited.insert(position, (cost, from));
         }
     }
-    assert!(visited.contains_key(&end));
+    Ok(tree)
+}
 
-    // Backtrack via the visited map to get the path from end to start - then reverse it.
-    let mut reverse_path = Vec::new();
+/// Searches from `start` until a position satisfying `is_goal` is settled,
+/// see `grow_search_tree`.
+///
+/// Returns the positions from `start` to the goal inclusive, and the total cost,
+/// or `None` if no goal can be reached.
+fn best_first_search<P: Copy + Eq + Hash>(
+    start: P,
+    is_goal: impl Fn(P) -> bool,
+    heuristic: impl Fn(P) -> usize,
+    expand: impl FnMut(Expansion<P>, &mut Vec<Relaxation<P>>),
+    reopen: bool,
+    stats: &mut SearchStats,
+    observer: &mut impl SearchObserver,
+) -> Result<Option<(Vec<P>, usize)>, RoutingError> {
+    let mut goal = None;
+    let tree = grow_search_tree(
+        start,
+        |position| {
+            if is_goal(position) {
+                goal = Some(position);
+            }
+            goal.is_some()
+        },
+        heuristic,
+        expand,
+        reopen,
+        stats,
+        observer,
+    )?;
+    Ok(goal.map(|goal| (tree.path(goal).unwrap(), tree.cost(goal).unwrap())))
+}
 
-    let mut curr = end;
-    while curr != start {
-        reverse_path.push((curr.0 as usize, curr.1 as usize));
-        curr = *visited.get(&curr).unwrap();
+/// Pushes the ways of reaching the neighbours of a cell, for the routers that search
+/// over plain cells of the height map.
+fn expand_cell(
+    curr: Expansion<(usize, usize)>,
+    relaxations: &mut Vec<Relaxation<(usize, usize)>>,
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+) {
+    for neighbor in height_map.neighbors(curr.position, connectivity) {
+        // Theta*: try to skip the current cell and go straight from its parent.
+        if connectivity == Connectivity::AnyAngle
+            && curr.parent != curr.position
+            && line_of_sight(height_map, cost_model, curr.parent, neighbor)
+        {
+            if let Some(step_cost) = cost_model.step_cost(height_map, curr.parent, neighbor) {
+                relaxations.push(Relaxation {
+                    position: neighbor,
+                    from: curr.parent,
+                    step_cost,
+                });
+            }
+        }
+        if let Some(step_cost) = cost_model.step_cost(height_map, curr.position, neighbor) {
+            relaxations.push(Relaxation {
+                position: neighbor,
+                from: curr.position,
+                step_cost,
+            });
+        }
     }
-    reverse_path.push((start.0 as usize, start.1 as usize));
+}
 
-    reverse_path.reverse();
-    reverse_path
+/// A* search from `start` to `end`.
+///
+/// `heuristic(cell, end, height_map)` must never overestimate the remaining cost under
+/// `cost_model`, otherwise the returned path is not guaranteed to be optimal.
+/// `CostModel::lower_bound` is always a valid choice.
+///
+/// Returns `RoutingError::NoFeasibleRoute` if the cost model forbids every way of
+/// reaching `end`, and the other `RoutingError`s for bad inputs.
+///
+/// With `Connectivity::AnyAngle` the result is usually, but not always, the optimal
+/// any-angle path: Theta* only considers straight segments back to the parent of the
+/// cell being expanded.
+fn a_star(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    heuristic: impl Fn((usize, usize), (usize, usize), &HeightMap) -> usize,
+) -> Result<(Route, SearchStats), RoutingError> {
+    a_star_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        heuristic,
+        &mut NoObserver,
+    )
+}
+
+/// Like `a_star`, but reports its progress to `observer`, which may cancel the search.
+fn a_star_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    heuristic: impl Fn((usize, usize), (usize, usize), &HeightMap) -> usize,
+    observer: &mut impl SearchObserver,
+) -> Result<(Route, SearchStats), RoutingError> {
+    check_bounds(height_map, &[start, end])?;
+
+    let mut stats = SearchStats::default();
+    let result = best_first_search(
+        start,
+        |position| position == end,
+        |position| heuristic(position, end, height_map),
+        |curr, relaxations| expand_cell(curr, relaxations, height_map, cost_model, connectivity),
+        connectivity.can_reopen(),
+        &mut stats,
+        observer,
+    )?;
+    let Some((path, cost)) = result else {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    };
+
+    Ok((Route { path, cost }, stats))
+}
+
+/// A* guided by `cost_model.lower_bound`, for routers that run one search after another.
+/// The expanded cells are added to `stats`, so that `observer` hears about all the
+/// searches together.
+fn a_star_with_stats(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    stats: &mut SearchStats,
+    observer: &mut impl SearchObserver,
+) -> Result<Route, RoutingError> {
+    check_bounds(height_map, &[start, end])?;
+
+    let result = best_first_search(
+        start,
+        |position| position == end,
+        |position| cost_model.lower_bound(height_map, position, end, connectivity),
+        |curr, relaxations| expand_cell(curr, relaxations, height_map, cost_model, connectivity),
+        connectivity.can_reopen(),
+        stats,
+        observer,
+    )?;
+    let Some((path, cost)) = result else {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    };
+    Ok(Route { path, cost })
 }
 
 fn main() {
-    // Stations are just 2d coordinates.
+    // Stations are just 2d coordinates. `platform::Platform` gives them a length and an axis,
+    // for `platform::route_stations`.
     let _station_coords = vec![(0, 0), (100, 0)];
 
     // Transit lines are an ordered list of stations, identified by their index in station_coords.
@@ -129,6 +730,7 @@ fn main() {
 
     // The path connecting two stations consists of a series of straight lines.
     // This is represented by a series of points that, when connected, connect the two stations together.
+    // `simplify::simplify_path` and `simplify::to_segments` turn a routed path into this form.
     // TODO: Instead of using coordinates, use indices/references to the station_coords array.
     let transit_line_paths = vec![vec![vec![((0, 0), (100, 100))]]];
 
@@ -156,6 +758,49 @@ fn main() {
     }
 
     // println!("{:?}", voxels);
+
+    // Route across some rolling hills, and show how far the search had to look around the
+    // route: the explored cells go on top of the terrain in three bands of settled cost,
+    // with the route itself above them.
+    let height_map = HeightMap::from_fn(64, 64, |(x, y)| {
+        ((x as f64 / 8.0).sin() * 6.0 + (y as f64 / 11.0).cos() * 4.0) as i32 + 10
+    });
+    let (route, search_trace) = trace::traced_dijkstra(
+        (0, 0),
+        (63, 63),
+        &height_map,
+        &cost_model::AbsoluteClimb::default(),
+        Connectivity::Eight,
+    )
+    .expect("the hills have no impassable cells");
+    let route_3d = route
+        .path
+        .iter()
+        .map(|&(x, y)| (x, y, height_map[(x, y)].max(0) as usize + 2))
+        .collect::<Vec<_>>();
+    let height_map_3d = height_map
+        .cells()
+        .map(|(x, y)| (x, y, height_map[(x, y)].max(0) as usize))
+        .collect::<Vec<_>>();
+    let explored_3d = search_trace.vox_layers(trace::TraceLayer::Cost, &height_map, 3);
+    let models = [&route_3d, &height_map_3d]
+        .into_iter()
+        .chain(&explored_3d)
+        .map(Vec::as_slice)
+        .collect::<Vec<_>>();
+    // The block covers the height map, and is as tall as the highest voxel.
+    let (width, depth) = height_map.size();
+    let height = models
+        .iter()
+        .flat_map(|model| model.iter())
+        .map(|&(_, _, z)| z + 1)
+        .max()
+        .unwrap_or(1);
+    magica_voxel::write_to_vox(
+        (width as u32, depth as u32, height as u32),
+        &models,
+        "output.vox".into(),
+    );
 }
 
 #[cfg(test)]
@@ -164,11 +809,17 @@ mod tests {
         collections::HashSet,
         fs::File,
         io::{BufRead, BufReader},
+        ops::ControlFlow,
         path::PathBuf,
     };
 
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use cost_model::{
+        AbsoluteClimb, FlatDistance, MaxGrade, NoGo, SharedTrack, SquaredGrade, SteepSteps,
+        UphillPenalty, WithRaster, COST_PER_CELL,
+    };
+    use datatypes::Raster;
 
     fn test_valid_manhattan_path(
         start: (usize, usize),
@@ -212,19 +863,76 @@ mod tests {
         Ok(())
     }
 
+    /// Like `test_valid_manhattan_path`, but diagonal steps are allowed.
+    fn test_valid_8_connected_path(
+        start: (usize, usize),
+        end: (usize, usize),
+        path: &[(usize, usize)],
+    ) -> Result<(), String> {
+        test_valid_any_angle_path(start, end, path)?;
+        for step in path.windows(2) {
+            if step[0].0.abs_diff(step[1].0) > 1 || step[0].1.abs_diff(step[1].1) > 1 {
+                return Err(format!(
+                    "Path is not 8-connected between these points: from: {:?}, to: {:?}",
+                    step[0], step[1]
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the endpoints and that no point is visited twice.
+    /// Consecutive points may be arbitrarily far apart.
+    fn test_valid_any_angle_path(
+        start: (usize, usize),
+        end: (usize, usize),
+        path: &[(usize, usize)],
+    ) -> Result<(), String> {
+        if path.first() != Some(&start) || path.last() != Some(&end) {
+            return Err(format!(
+                "Path does not run from {:?} to {:?}: {:?}",
+                start, end, path
+            ));
+        }
+        let mut visited = HashSet::new();
+        for point in path {
+            if !visited.insert(*point) {
+                return Err(format!("Path contained a duplicate point: {:?}", point));
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_basic() {
         let start = (0, 0);
         let end = (9, 9);
-        let height_map = vec![vec![0; 10]; 10];
-        let path = dijkstra(start, end, &height_map);
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let path = dijkstra(
+            start,
+            end,
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Four,
+        )
+        .unwrap()
+        .path;
         // dbg!(&path);
 
         test_valid_manhattan_path(start, end, &path).unwrap();
     }
 
-    #[test]
-    fn from_height_map_256_256() {
+    fn path_cost(
+        height_map: &HeightMap,
+        cost_model: &impl CostModel,
+        path: &[(usize, usize)],
+    ) -> usize {
+        path.windows(2)
+            .map(|w| cost_model.step_cost(height_map, w[0], w[1]).unwrap())
+            .sum()
+    }
+
+    pub(crate) fn load_height_map_256_256() -> HeightMap {
         let mut height_map_path = PathBuf::from(format!(
             "{}/{}",
             env!("CARGO_MANIFEST_DIR"),
@@ -236,7 +944,7 @@ mod tests {
         let buf = BufReader::new(height_map_file);
         // The file is assumed to be single-space separated integers with no trailing spaces.
         // Each row is separated by a new line, and there is no newline at the end.
-        let height_map: Vec<Vec<i32>> = buf
+        let height_map = buf
             .lines()
             .map(|line| {
                 line.unwrap()
@@ -245,36 +953,634 @@ mod tests {
                     .map(|s| s.parse::<i32>().unwrap().min(254))
                     .collect()
             })
-            .collect();
+            .collect::<Vec<Vec<i32>>>();
 
-        assert!(height_map.len() == 256 && height_map[0].len() == 256);
+        let height_map = HeightMap::from_columns(height_map);
+        assert_eq!(height_map.size(), (256, 256));
+        height_map
+    }
+
+    /// Deterministic xorshift generator so the cross-check tests are reproducible
+    /// without pulling in a `rand` dependency.
+    struct XorShift(u64);
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn height_map(&mut self, x_len: usize, y_len: usize, max_height: i32) -> HeightMap {
+            HeightMap::from_fn(x_len, y_len, |_| {
+                (self.next() % (max_height as u64 + 1)) as i32
+            })
+        }
+    }
+
+    /// Exhaustively enumerates every simple 4-connected path and returns the cheapest cost.
+    fn brute_force_cost(
+        start: (usize, usize),
+        end: (usize, usize),
+        height_map: &HeightMap,
+        cost_model: &impl CostModel,
+    ) -> usize {
+        fn walk(
+            curr: (usize, usize),
+            end: (usize, usize),
+            height_map: &HeightMap,
+            cost_model: &impl CostModel,
+            path: &mut Vec<(usize, usize)>,
+            best: &mut usize,
+        ) {
+            if curr == end {
+                *best = (*best).min(path_cost(height_map, cost_model, path));
+                return;
+            }
+            for next in height_map.neighbors(curr, Connectivity::Four) {
+                if path.contains(&next) {
+                    continue;
+                }
+                path.push(next);
+                walk(next, end, height_map, cost_model, path, best);
+                path.pop();
+            }
+        }
+        let mut best = usize::MAX;
+        walk(
+            start,
+            end,
+            height_map,
+            cost_model,
+            &mut vec![start],
+            &mut best,
+        );
+        best
+    }
+
+    #[test]
+    fn dijkstra_matches_brute_force() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..50 {
+            let x_len = 2 + (rng.next() % 3) as usize;
+            let y_len = 2 + (rng.next() % 3) as usize;
+            let height_map = rng.height_map(x_len, y_len, 9);
+            let start = (
+                (rng.next() % x_len as u64) as usize,
+                (rng.next() % y_len as u64) as usize,
+            );
+            let end = (
+                (rng.next() % x_len as u64) as usize,
+                (rng.next() % y_len as u64) as usize,
+            );
+
+            let cost_models: [&dyn CostModel; 4] = [
+                &FlatDistance,
+                &AbsoluteClimb::default(),
+                &SquaredGrade { weight: 2.0 },
+                &UphillPenalty { weight: 3.0 },
+            ];
+            for cost_model in cost_models {
+                let route =
+                    dijkstra(start, end, &height_map, &cost_model, Connectivity::Four).unwrap();
+                test_valid_manhattan_path(start, end, &route.path).unwrap();
+                assert_eq!(route.cost, path_cost(&height_map, &cost_model, &route.path));
+                assert_eq!(
+                    route.cost,
+                    brute_force_cost(start, end, &height_map, &cost_model),
+                    "height map: {:?}, start: {:?}, end: {:?}",
+                    height_map,
+                    start,
+                    end
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_star_matches_dijkstra_with_fewer_expansions() {
+        let height_map = load_height_map_256_256();
+
+        let start = (0, 0);
+        let end = (255, 255);
+        let cost_model = AbsoluteClimb::default();
+        let (dijkstra_route, dijkstra_stats) = a_star(
+            start,
+            end,
+            &height_map,
+            &cost_model,
+            Connectivity::Four,
+            |_, _, _| 0,
+        )
+        .unwrap();
+        let (a_star_route, a_star_stats) = a_star(
+            start,
+            end,
+            &height_map,
+            &cost_model,
+            Connectivity::Four,
+            |from, to, height_map| cost_model.lower_bound(height_map, from, to, Connectivity::Four),
+        )
+        .unwrap();
+
+        test_valid_manhattan_path(start, end, &a_star_route.path).unwrap();
+        assert_eq!(
+            dijkstra_route,
+            dijkstra(start, end, &height_map, &cost_model, Connectivity::Four).unwrap()
+        );
+        // Ties can be broken differently, but both paths must be optimal.
+        assert_eq!(a_star_route.cost, dijkstra_route.cost);
+        assert_eq!(
+            path_cost(&height_map, &cost_model, &a_star_route.path),
+            a_star_route.cost
+        );
+        assert!(
+            a_star_stats.expanded < dijkstra_stats.expanded,
+            "A* expanded {} cells, dijkstra expanded {}",
+            a_star_stats.expanded,
+            dijkstra_stats.expanded
+        );
+    }
+
+    #[test]
+    fn a_star_is_optimal_with_inconsistent_heuristics() {
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        let cost_model = AbsoluteClimb::default();
+        for _ in 0..50 {
+            let height_map = rng.height_map(12, 12, 20);
+            let start = ((rng.next() % 12) as usize, (rng.next() % 12) as usize);
+            let end = ((rng.next() % 12) as usize, (rng.next() % 12) as usize);
+            // Scaling the lower bound by a different fraction in every cell keeps it
+            // admissible, but neighbouring cells no longer agree, so it isn't consistent.
+            let fractions = rng.height_map(12, 12, 100);
+            let heuristic = |from: (usize, usize), to, height_map: &HeightMap| {
+                cost_model.lower_bound(height_map, from, to, Connectivity::Eight)
+                    * fractions[from] as usize
+                    / 100
+            };
+
+            for connectivity in [Connectivity::Four, Connectivity::Eight] {
+                let (route, _) = a_star(
+                    start,
+                    end,
+                    &height_map,
+                    &cost_model,
+                    connectivity,
+                    heuristic,
+                )
+                .unwrap();
+                let expected = dijkstra(start, end, &height_map, &cost_model, connectivity)
+                    .unwrap()
+                    .cost;
+                assert_eq!(route.cost, expected);
+                assert_eq!(route.cost, path_cost(&height_map, &cost_model, &route.path));
+            }
+        }
+    }
+
+    #[test]
+    fn lower_bounds_follow_connectivity() {
+        let height_map = load_height_map_256_256();
+        let cost_model = AbsoluteClimb::default();
+        let (start, end) = ((0, 0), (255, 255));
+
+        // The Euclidean bound holds for every connectivity, but the Manhattan and octile
+        // bounds are tighter for 4- and 8-connected paths.
+        for connectivity in [Connectivity::Four, Connectivity::Eight] {
+            let (euclidean, euclidean_stats) = a_star(
+                start,
+                end,
+                &height_map,
+                &cost_model,
+                connectivity,
+                |from, to, height_map| {
+                    cost_model.lower_bound(height_map, from, to, Connectivity::AnyAngle)
+                },
+            )
+            .unwrap();
+            let (tight, tight_stats) = a_star(
+                start,
+                end,
+                &height_map,
+                &cost_model,
+                connectivity,
+                |from, to, height_map| cost_model.lower_bound(height_map, from, to, connectivity),
+            )
+            .unwrap();
+
+            assert_eq!(tight.cost, euclidean.cost);
+            assert!(
+                tight_stats.expanded < euclidean_stats.expanded,
+                "{:?}: the tight bound expanded {} cells, the Euclidean one {}",
+                connectivity,
+                tight_stats.expanded,
+                euclidean_stats.expanded
+            );
+        }
+    }
+
+    #[test]
+    fn max_grade_forbids_steep_steps() {
+        let height_map = load_height_map_256_256();
+        let cost_model = MaxGrade {
+            inner: AbsoluteClimb::default(),
+            max_grade: 12.0,
+            steep_steps: SteepSteps::Forbid,
+        };
+
+        let start = (0, 0);
+        let end = (255, 255);
+        let route = dijkstra(start, end, &height_map, &cost_model, Connectivity::Four).unwrap();
+        test_valid_manhattan_path(start, end, &route.path).unwrap();
+        for step in route.path.windows(2) {
+            assert!(!cost_model.is_too_steep(&height_map, step[0], step[1]));
+        }
+
+        // A cliff runs across the whole map between the start and the end.
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| if x == 2 { 100 } else { 0 });
+        let end = (9, 9);
+        assert_eq!(
+            dijkstra(start, end, &height_map, &cost_model, Connectivity::Four),
+            Err(RoutingError::NoFeasibleRoute { start, end })
+        );
+    }
+
+    #[test]
+    fn bad_inputs_return_errors() {
+        let cost_model = AbsoluteClimb::default();
+        for empty in [HeightMap::new(0, 0, vec![]), HeightMap::new(3, 0, vec![])] {
+            assert_eq!(
+                dijkstra((0, 0), (0, 0), &empty, &cost_model, Connectivity::Four),
+                Err(RoutingError::EmptyHeightMap)
+            );
+        }
+
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        assert_eq!(
+            dijkstra(
+                (0, 0),
+                (10, 3),
+                &height_map,
+                &cost_model,
+                Connectivity::Four
+            ),
+            Err(RoutingError::OutOfBounds {
+                point: (10, 3),
+                size: (10, 10)
+            })
+        );
+
+        let cost_model = WithRaster {
+            inner: cost_model,
+            raster: height_map.raster(usize::MAX / 4),
+        };
+        assert_eq!(
+            dijkstra((0, 0), (9, 9), &height_map, &cost_model, Connectivity::Four),
+            Err(RoutingError::CostOverflow)
+        );
+    }
+
+    #[test]
+    fn route_via_waypoints_around_no_go_zones() {
+        // A lake covers the middle of the map.
+        let mask = Raster::from_fn(10, 10, |(x, y)| (3..7).contains(&x) && (2..8).contains(&y));
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let cost_model = NoGo {
+            inner: FlatDistance,
+            mask,
+        };
+
+        let start = (0, 0);
+        let via = [(9, 0), (9, 9)];
+        let end = (0, 9);
+        let route = route_via(
+            start,
+            &via,
+            end,
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+        )
+        .unwrap();
+
+        test_valid_any_angle_path(start, (9, 0), &route.path[..10]).unwrap();
+        for waypoint in via {
+            assert!(route.path.contains(&waypoint));
+        }
+        assert_eq!(route.path.last(), Some(&end));
+        assert!(route.path.iter().all(|&cell| !cost_model.mask[cell]));
+        assert_eq!(route.cost, 27 * COST_PER_CELL);
+
+        // The second via point is in the lake.
+        assert_eq!(
+            route_via(
+                start,
+                &[(9, 0), (5, 5)],
+                end,
+                &height_map,
+                &cost_model,
+                Connectivity::Eight,
+            ),
+            Err(RoutingError::LegFailed {
+                leg: 1,
+                error: Box::new(RoutingError::NoFeasibleRoute {
+                    start: (9, 0),
+                    end: (5, 5)
+                })
+            })
+        );
+    }
+
+    #[test]
+    fn route_via_counts_expansions_across_legs() {
+        /// Keeps the expansion count of every report, and cancels once `limit` positions
+        /// are expanded.
+        struct Counter {
+            reports: Vec<usize>,
+            limit: usize,
+        }
+        impl SearchObserver for Counter {
+            fn progress(&mut self, progress: &Progress) -> ControlFlow<()> {
+                self.reports.push(progress.expanded);
+                if progress.expanded >= self.limit {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            }
+
+            fn interval(&self) -> usize {
+                1
+            }
+        }
+
+        let height_map = HeightMap::from_fn(20, 20, |(x, y)| (x as i32 * 7 + y as i32 * 13) % 11);
+        let cost_model = AbsoluteClimb::default();
+        let waypoints = [(0, 0), (19, 3), (4, 17), (15, 15)];
+        let mut counter = Counter {
+            reports: Vec::new(),
+            limit: usize::MAX,
+        };
+        let route = route_via_observed(
+            waypoints[0],
+            &waypoints[1..3],
+            waypoints[3],
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            &mut counter,
+        )
+        .unwrap();
+        assert_eq!(
+            route,
+            route_via(
+                waypoints[0],
+                &waypoints[1..3],
+                waypoints[3],
+                &height_map,
+                &cost_model,
+                Connectivity::Eight
+            )
+            .unwrap()
+        );
+
+        // The count carries on from one leg to the next. Reaching the end of a leg counts
+        // as an expansion, but isn't reported.
+        let mut leg_ends = Vec::new();
+        let mut total = 0;
+        for pair in waypoints.windows(2) {
+            let (_, stats) = a_star(
+                pair[0],
+                pair[1],
+                &height_map,
+                &cost_model,
+                Connectivity::Eight,
+                |from, to, height_map| {
+                    cost_model.lower_bound(height_map, from, to, Connectivity::Eight)
+                },
+            )
+            .unwrap();
+            total += stats.expanded;
+            leg_ends.push(total);
+        }
+        let expected = (1..=total)
+            .filter(|expanded| !leg_ends.contains(expanded))
+            .collect::<Vec<_>>();
+        assert_eq!(counter.reports, expected);
+
+        // Cancelling during a later leg isn't a failed leg.
+        let mut counter = Counter {
+            reports: Vec::new(),
+            limit: leg_ends[0] + 1,
+        };
+        let result = route_via_observed(
+            waypoints[0],
+            &waypoints[1..3],
+            waypoints[3],
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            &mut counter,
+        );
+        let Err(RoutingError::Cancelled { progress }) = result else {
+            panic!("expected the search to be cancelled, got {:?}", result);
+        };
+        assert_eq!(progress.expanded, leg_ends[0] + 1);
+    }
+
+    #[test]
+    fn second_line_shares_existing_track() {
+        let height_map = HeightMap::new(30, 30, vec![0; 30 * 30]);
+        let mut cost_model = SharedTrack {
+            inner: FlatDistance,
+            existing: height_map.raster(false),
+            shared_cost_fraction: 0.1,
+            junction_penalty: 1.0,
+        };
+
+        let first_line = dijkstra(
+            (0, 10),
+            (29, 10),
+            &height_map,
+            &cost_model,
+            Connectivity::Four,
+        )
+        .unwrap();
+        cost_model.add_path(&first_line.path);
+
+        let second_line = dijkstra(
+            (0, 12),
+            (29, 12),
+            &height_map,
+            &cost_model,
+            Connectivity::Four,
+        )
+        .unwrap();
+        assert!(second_line.path.contains(&(15, 10)));
+        // 2 steps to join, 29 shared steps, 2 steps and a junction to leave.
+        assert_eq!(second_line.cost, 2000 + 29 * 100 + 2000 + 1000);
+        assert_eq!(
+            second_line.cost,
+            path_cost(&height_map, &cost_model, &second_line.path)
+        );
+
+        // Leaving is too expensive, so the second line lays its own track.
+        cost_model.junction_penalty = 30.0;
+        let second_line = dijkstra(
+            (0, 12),
+            (29, 12),
+            &height_map,
+            &cost_model,
+            Connectivity::Four,
+        )
+        .unwrap();
+        assert!(!second_line.path.contains(&(15, 10)));
+        assert_eq!(second_line.cost, 29 * COST_PER_CELL);
+    }
+
+    #[test]
+    fn max_grade_builds_structures_over_steep_steps() {
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| if x == 2 { 100 } else { 0 });
+        let cost_model = MaxGrade {
+            inner: AbsoluteClimb::default(),
+            max_grade: 1.0,
+            steep_steps: SteepSteps::Structure { cost: 20.0 },
+        };
+
+        let start = (0, 0);
+        let end = (9, 0);
+        let route = dijkstra(start, end, &height_map, &cost_model, Connectivity::Four).unwrap();
+        test_valid_manhattan_path(start, end, &route.path).unwrap();
+        // Straight across: 7 flat steps, and 2 steep steps onto and off the ridge.
+        assert_eq!(route.cost, (7 + 2 * 20) * COST_PER_CELL);
+    }
+
+    #[test]
+    fn eight_connected_costs_diagonals_by_length() {
+        let start = (0, 0);
+        let end = (9, 9);
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let route = dijkstra(start, end, &height_map, &FlatDistance, Connectivity::Eight).unwrap();
+
+        test_valid_8_connected_path(start, end, &route.path).unwrap();
+        assert_eq!(route.path.len(), 10);
+        assert_eq!(
+            route.cost,
+            9 * FlatDistance.step_cost(&height_map, (0, 0), (1, 1)).unwrap()
+        );
+    }
+
+    #[test]
+    fn any_angle_goes_straight_on_flat_ground() {
+        let start = (0, 0);
+        let end = (9, 3);
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let route = dijkstra(
+            start,
+            end,
+            &height_map,
+            &FlatDistance,
+            Connectivity::AnyAngle,
+        )
+        .unwrap();
+
+        assert_eq!(route.path, vec![start, end]);
+        assert_eq!(
+            route.cost,
+            FlatDistance.step_cost(&height_map, start, end).unwrap()
+        );
+    }
+
+    #[test]
+    fn routing_modes_on_height_map_256_256() {
+        let height_map = load_height_map_256_256();
+        let cost_model = AbsoluteClimb::default();
+
+        let start = (0, 0);
+        let end = (255, 255);
+        let four = dijkstra(start, end, &height_map, &cost_model, Connectivity::Four).unwrap();
+        let eight = dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight).unwrap();
+        let any_angle =
+            dijkstra(start, end, &height_map, &cost_model, Connectivity::AnyAngle).unwrap();
+
+        test_valid_8_connected_path(start, end, &eight.path).unwrap();
+        test_valid_any_angle_path(start, end, &any_angle.path).unwrap();
+        assert_eq!(eight.cost, path_cost(&height_map, &cost_model, &eight.path));
+        assert_eq!(
+            any_angle.cost,
+            path_cost(&height_map, &cost_model, &any_angle.path)
+        );
+        for segment in any_angle.path.windows(2) {
+            assert!(line_of_sight(
+                &height_map,
+                &cost_model,
+                segment[0],
+                segment[1]
+            ));
+        }
+        // Every 4-connected path is also an 8-connected path.
+        assert!(eight.cost <= four.cost);
+        assert!(any_angle.path.len() < eight.path.len());
+    }
+
+    #[test]
+    fn from_height_map_256_256() {
+        let height_map = load_height_map_256_256();
 
         let start = (0, 0);
         let end = (255, 255);
-        let path = dijkstra(start, end, &height_map);
+        let (route, search_trace) = trace::traced_dijkstra(
+            start,
+            end,
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Four,
+        )
+        .unwrap();
+        let path = route.path;
 
         // dbg!(&path);
 
         test_valid_manhattan_path(start, end, &path).unwrap();
 
-        let path_3d = path
+        // Lay the track at a limited grade rather than right on top of the terrain.
+        let profile = profile::optimize_profile(
+            &path,
+            &height_map,
+            profile::VerticalLimits {
+                max_grade: 1.0,
+                max_grade_change: 1.0,
+                elevation_step: 1.0,
+            },
+            profile::ProfileCosts {
+                cut: 1.0,
+                fill: 1.0,
+                max_cut_depth: 5.0,
+                max_fill_height: 5.0,
+                tunnel: Some(10.0),
+                bridge: Some(10.0),
+            },
+        )
+        .unwrap();
+        let path_3d = profile
+            .points
             .into_iter()
-            .map(|(x, y)| (x, y, height_map[x][y] as usize + 1))
+            .map(|(x, y, z)| (x, y, z as usize + 1))
             .collect::<Vec<_>>();
+        // How far the search got, coloured by settled cost.
+        let explored_3d = search_trace.vox_layers(trace::TraceLayer::Cost, &height_map, 3);
         let height_map_3d = height_map
-            .into_iter()
-            .enumerate()
-            .flat_map(|(x, col_points)| {
-                col_points
-                    .into_iter()
-                    .enumerate()
-                    .map(|(y, z)| (x, y, z as usize))
-                    .collect::<Vec<_>>()
-            })
+            .cells()
+            .map(|(x, y)| (x, y, height_map[(x, y)] as usize))
             .collect::<Vec<_>>();
         magica_voxel::write_to_vox(
             (256, 256, 256),
-            &[&path_3d[..], &height_map_3d[..]],
+            &[
+                &path_3d[..],
+                &height_map_3d[..],
+                &explored_3d[0][..],
+                &explored_3d[1][..],
+                &explored_3d[2][..],
+            ],
             // &[&path_3d[..]],
             "output.vox".into(),
         );
@@ -0,0 +1,404 @@
+// Multi-objective routing: instead of folding every concern into one cost, keep every route
+// that isn't beaten on all objectives at once, so that the trade-offs stay visible.
+
+use std::collections::BinaryHeap;
+
+use crate::{
+    check_bounds,
+    cost_model::CostModel,
+    datatypes::HeightMap,
+    progress::{self, NoObserver, Progress, SearchObserver},
+    Connectivity, HeapState, RoutingError,
+};
+
+/// A route that no other route beats under every objective.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParetoRoute {
+    /// Every cell on the path, from the start to the end inclusive.
+    pub path: Vec<(usize, usize)>,
+    /// The cost under each objective, in the order the objectives were given.
+    pub costs: Vec<usize>,
+}
+
+/// One way of reaching a cell, with what it costs under each objective.
+struct Label {
+    cell: (usize, usize),
+    costs: Vec<usize>,
+    /// The label this one was extended from, `None` for the start.
+    parent: Option<usize>,
+    /// Set once a label at the same cell that is at least as cheap under every objective
+    /// turns up, so that this one is no longer worth expanding.
+    dominated: bool,
+}
+
+/// Whether `costs` are at most `1 + epsilon` times `other` under every objective.
+fn covers(costs: &[usize], other: &[usize], epsilon: f64) -> bool {
+    costs
+        .iter()
+        .zip(other)
+        .all(|(&cost, &other)| cost as f64 <= other as f64 * (1.0 + epsilon))
+}
+
+/// Finds the Pareto-optimal routes from `start` to `end` under `objectives`: the routes for
+/// which no other route costs the same or less under every objective.
+///
+/// This is multi-objective A* (NAMOA*), which keeps the set of non-dominated labels of every
+/// cell and uses each objective's `lower_bound` to skip labels that can't lead to a new
+/// route. A step is only allowed if every objective allows it, and of several routes with
+/// the same costs only one is kept. `Connectivity::AnyAngle` is treated like
+/// `Connectivity::Eight`.
+///
+/// The number of labels can grow quickly with the size of the map. With `epsilon > 0`, a
+/// label is also dropped when another one at the same cell costs at most `1 + epsilon`
+/// times as much under every objective, which gives far fewer routes that still roughly
+/// cover the whole frontier.
+///
+/// The routes are sorted by their cost under the first objective. Having no objectives,
+/// or a negative or NaN `epsilon`, is a `RoutingError::InvalidSetting`.
+pub fn pareto_routes(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    objectives: &[&dyn CostModel],
+    connectivity: Connectivity,
+    epsilon: f64,
+) -> Result<Vec<ParetoRoute>, RoutingError> {
+    pareto_routes_observed(
+        start,
+        end,
+        height_map,
+        objectives,
+        connectivity,
+        epsilon,
+        &mut NoObserver,
+    )
+}
+
+/// Like `pareto_routes`, but reports its progress to `observer`, which may cancel the
+/// search. Each expanded label counts as an expanded position, and the costs in the
+/// `Progress` are summed over all objectives.
+pub fn pareto_routes_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    objectives: &[&dyn CostModel],
+    connectivity: Connectivity,
+    epsilon: f64,
+    observer: &mut impl SearchObserver,
+) -> Result<Vec<ParetoRoute>, RoutingError> {
+    if objectives.is_empty() {
+        return Err(RoutingError::InvalidSetting {
+            name: "objectives",
+            reason: "must have at least one objective",
+        });
+    }
+    if epsilon.is_nan() || epsilon < 0.0 {
+        return Err(RoutingError::InvalidSetting {
+            name: "epsilon",
+            reason: "must be a non-negative number",
+        });
+    }
+    check_bounds(height_map, &[start, end])?;
+    let connectivity = match connectivity {
+        Connectivity::AnyAngle => Connectivity::Eight,
+        connectivity => connectivity,
+    };
+
+    // The least each objective could cost in total, going through `cell` at `costs`.
+    let estimate = |cell: (usize, usize), costs: &[usize]| {
+        objectives
+            .iter()
+            .zip(costs)
+            .map(|(objective, &cost)| {
+                cost.saturating_add(objective.lower_bound(height_map, cell, end, connectivity))
+            })
+            .collect::<Vec<_>>()
+    };
+    // Labels are expanded in order of the sum of their estimates over all objectives, so
+    // that no label can be dominated by one that is expanded after it.
+    let sum = |costs: &[usize]| {
+        costs
+            .iter()
+            .fold(0usize, |sum, &cost| sum.saturating_add(cost))
+    };
+
+    let mut labels = vec![Label {
+        cell: start,
+        costs: vec![0; objectives.len()],
+        parent: None,
+        dominated: false,
+    }];
+    // The labels of every cell that haven't been dominated.
+    let mut cell_labels = height_map.raster(Vec::new());
+    cell_labels[start].push(0);
+    let mut frontier = BinaryHeap::new();
+    frontier.push(HeapState::new(
+        0,
+        sum(&estimate(start, &labels[0].costs)),
+        0,
+    ));
+    // The labels that reached `end`.
+    let mut found: Vec<usize> = Vec::new();
+    let mut expanded = 0;
+
+    while let Some(HeapState {
+        position: index, ..
+    }) = frontier.pop()
+    {
+        if labels[index].dominated {
+            continue;
+        }
+        let (cell, costs) = (labels[index].cell, labels[index].costs.clone());
+        // Anything this label leads to is no better than a route that was already found.
+        let estimated = estimate(cell, &costs);
+        if found
+            .iter()
+            .any(|&route| covers(&labels[route].costs, &estimated, epsilon))
+        {
+            continue;
+        }
+        if cell == end {
+            found.push(index);
+            continue;
+        }
+        expanded += 1;
+        progress::report(
+            observer,
+            Progress {
+                expanded,
+                frontier: frontier.len(),
+                cost: sum(&costs),
+                best_cost: sum(&estimated),
+            },
+        )?;
+
+        'neighbors: for next in height_map.neighbors(cell, connectivity) {
+            let mut next_costs = Vec::with_capacity(objectives.len());
+            for (objective, &cost) in objectives.iter().zip(&costs) {
+                let Some(step_cost) = objective.step_cost(height_map, cell, next) else {
+                    continue 'neighbors;
+                };
+                next_costs.push(
+                    cost.checked_add(step_cost)
+                        .ok_or(RoutingError::CostOverflow)?,
+                );
+            }
+
+            let existing = &mut cell_labels[next];
+            if existing
+                .iter()
+                .any(|&other| covers(&labels[other].costs, &next_costs, epsilon))
+            {
+                continue;
+            }
+            existing.retain(|&other| {
+                let dominated = covers(&next_costs, &labels[other].costs, 0.0);
+                labels[other].dominated |= dominated;
+                !dominated
+            });
+
+            let next_index = labels.len();
+            existing.push(next_index);
+            frontier.push(HeapState::new(
+                sum(&next_costs),
+                sum(&estimate(next, &next_costs)),
+                next_index,
+            ));
+            labels.push(Label {
+                cell: next,
+                costs: next_costs,
+                parent: Some(index),
+                dominated: false,
+            });
+        }
+    }
+
+    if found.is_empty() {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    }
+    let mut routes = found
+        .into_iter()
+        .map(|index| {
+            let mut reverse_path = vec![labels[index].cell];
+            let mut curr = index;
+            while let Some(parent) = labels[curr].parent {
+                reverse_path.push(labels[parent].cell);
+                curr = parent;
+            }
+            reverse_path.reverse();
+            ParetoRoute {
+                path: reverse_path,
+                costs: labels[index].costs.clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+    routes.sort_by(|a, b| a.costs.cmp(&b.costs));
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+
+    use super::*;
+    use crate::{
+        cost_model::{FlatDistance, HeightChange, COST_PER_CELL},
+        dijkstra,
+    };
+
+    /// A cone-shaped hill in the middle of the map, right between the stations.
+    fn hill() -> HeightMap {
+        HeightMap::from_fn(24, 24, |(x, y)| {
+            (8 - ((x as i32 - 12).abs() + (y as i32 - 12).abs()) / 2).max(0)
+        })
+    }
+
+    #[test]
+    fn trades_distance_against_climb() {
+        let height_map = hill();
+        let (start, end) = ((0, 12), (23, 12));
+        let objectives: [&dyn CostModel; 2] = [&FlatDistance, &HeightChange];
+
+        let routes = pareto_routes(
+            start,
+            end,
+            &height_map,
+            &objectives,
+            Connectivity::Eight,
+            0.0,
+        )
+        .unwrap();
+        assert!(routes.len() > 2, "{:?}", routes);
+
+        for route in &routes {
+            assert_eq!(route.path.first(), Some(&start));
+            assert_eq!(route.path.last(), Some(&end));
+            for (objective, &cost) in objectives.iter().zip(&route.costs) {
+                let path_cost = route
+                    .path
+                    .windows(2)
+                    .map(|step| objective.step_cost(&height_map, step[0], step[1]).unwrap())
+                    .sum::<usize>();
+                assert_eq!(path_cost, cost);
+            }
+            for other in &routes {
+                assert!(other == route || !covers(&other.costs, &route.costs, 0.0));
+            }
+        }
+
+        // The ends of the frontier are the best routes under each objective on its own.
+        for (i, objective) in objectives.iter().enumerate() {
+            let best = dijkstra(start, end, &height_map, objective, Connectivity::Eight).unwrap();
+            let cheapest = routes.iter().map(|route| route.costs[i]).min();
+            assert_eq!(cheapest, Some(best.cost));
+        }
+        assert_eq!(routes[0].costs[0], 23 * COST_PER_CELL);
+        // Going around the hill only climbs the height difference between the stations.
+        assert_eq!(routes.last().unwrap().costs[1], COST_PER_CELL);
+
+        let single = pareto_routes(
+            start,
+            end,
+            &height_map,
+            &objectives[..1],
+            Connectivity::Eight,
+            0.0,
+        )
+        .unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].costs, vec![23 * COST_PER_CELL]);
+    }
+
+    #[test]
+    fn rejects_invalid_settings_and_can_be_cancelled() {
+        let height_map = hill();
+        let (start, end) = ((0, 12), (23, 12));
+        let objectives: [&dyn CostModel; 2] = [&FlatDistance, &HeightChange];
+        let route = |objectives: &[&dyn CostModel], epsilon| {
+            pareto_routes(
+                start,
+                end,
+                &height_map,
+                objectives,
+                Connectivity::Eight,
+                epsilon,
+            )
+        };
+
+        assert!(matches!(
+            route(&[], 0.0),
+            Err(RoutingError::InvalidSetting { .. })
+        ));
+        for epsilon in [-0.1, f64::NAN] {
+            assert!(matches!(
+                route(&objectives, epsilon),
+                Err(RoutingError::InvalidSetting { .. })
+            ));
+        }
+
+        /// Cancels at the first report.
+        struct Impatient;
+        impl SearchObserver for Impatient {
+            fn progress(&mut self, _progress: &Progress) -> ControlFlow<()> {
+                ControlFlow::Break(())
+            }
+
+            fn interval(&self) -> usize {
+                50
+            }
+        }
+        assert!(matches!(
+            pareto_routes_observed(
+                start,
+                end,
+                &height_map,
+                &objectives,
+                Connectivity::Eight,
+                0.0,
+                &mut Impatient,
+            ),
+            Err(RoutingError::Cancelled {
+                progress: Progress { expanded: 50, .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn epsilon_thins_out_the_frontier() {
+        let height_map = hill();
+        let (start, end) = ((0, 12), (23, 12));
+        let objectives: [&dyn CostModel; 2] = [&FlatDistance, &HeightChange];
+
+        let exact = pareto_routes(
+            start,
+            end,
+            &height_map,
+            &objectives,
+            Connectivity::Eight,
+            0.0,
+        )
+        .unwrap();
+        let approximate = pareto_routes(
+            start,
+            end,
+            &height_map,
+            &objectives,
+            Connectivity::Eight,
+            0.1,
+        )
+        .unwrap();
+
+        assert!(!approximate.is_empty());
+        assert!(approximate.len() < exact.len());
+        // Every exact route is roughly matched by an approximate one.
+        for route in &exact {
+            assert!(
+                approximate
+                    .iter()
+                    .any(|other| covers(&other.costs, &route.costs, 0.25)),
+                "{:?} isn't covered",
+                route.costs
+            );
+        }
+    }
+}
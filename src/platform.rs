@@ -0,0 +1,479 @@
+// Stations as platforms: straight, level runs of track that routes have to arrive at along
+// their axis, instead of single cells.
+
+use std::ops::Range;
+
+use crate::{
+    a_star_with_stats, check_bounds,
+    cost_model::{CostModel, NoGo},
+    datatypes::HeightMap,
+    profile::{optimize_level_profile, Profile, ProfileCosts, VerticalLimits},
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Route, RoutingError, SearchStats,
+};
+
+/// A station platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Platform {
+    pub center: (usize, usize),
+    /// The direction of the platform axis, as a step to one of the 8 neighbouring cells.
+    /// Trains may run along the platform either way.
+    pub direction: (i32, i32),
+    /// How many cells the platform covers, at least 1.
+    pub length: usize,
+}
+
+impl Platform {
+    /// Checks that the platform has a length and its direction is a step to a neighbour
+    /// that `connectivity` allows.
+    fn validate(&self, connectivity: Connectivity) -> Result<(), RoutingError> {
+        if self.length == 0 {
+            return Err(RoutingError::InvalidSetting {
+                name: "platform length",
+                reason: "must be at least 1",
+            });
+        }
+        if self.direction == (0, 0) || self.direction.0.abs() > 1 || self.direction.1.abs() > 1 {
+            return Err(RoutingError::InvalidSetting {
+                name: "platform direction",
+                reason: "must be a step to one of the 8 neighbouring cells",
+            });
+        }
+        if connectivity == Connectivity::Four && self.direction.0 != 0 && self.direction.1 != 0 {
+            return Err(RoutingError::InvalidSetting {
+                name: "platform direction",
+                reason: "can't be diagonal with 4-connectivity",
+            });
+        }
+        Ok(())
+    }
+
+    /// The cells of the platform, in `direction`, or `None` if some are off the low edges.
+    fn cells(&self) -> Option<Vec<(i32, i32)>> {
+        let back = (self.length as i32 - 1) / 2;
+        (0..self.length as i32)
+            .map(|i| {
+                let x = self.center.0 as i32 + (i - back) * self.direction.0;
+                let y = self.center.1 as i32 + (i - back) * self.direction.1;
+                (x >= 0 && y >= 0).then_some((x, y))
+            })
+            .collect()
+    }
+}
+
+/// A route through a series of stations, which runs the whole length of every platform.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StationRoute {
+    pub route: Route,
+    /// Where each platform is on `route.path`, in the order of the stations.
+    pub platforms: Vec<Range<usize>>,
+    /// The track elevations along `route.path`, level across every platform.
+    pub profile: Profile,
+}
+
+/// A route through some of the platforms: its path, its cost, and where each platform is
+/// on the path.
+type PartialRoute = (Vec<(usize, usize)>, usize, Vec<Range<usize>>);
+
+/// Routes through every platform of `stations` in order.
+///
+/// Each platform may be run through in either direction, and the route always arrives at
+/// and leaves a platform along its axis, from the cells just past its ends. Between
+/// stations the route is found with A* and never crosses a platform. The track elevations
+/// then come from `profile::optimize_level_profile` under `limits` and `costs`, with zero
+/// grade across every platform.
+///
+/// A leg that can't be routed in any direction fails with `RoutingError::LegFailed`, and
+/// a route without a level profile within `limits` with `RoutingError::NoFeasibleRoute`.
+/// Having no stations, or a platform without a length or direction (or with a diagonal
+/// one under `Connectivity::Four`), is a `RoutingError::InvalidSetting`.
+pub fn route_stations(
+    stations: &[Platform],
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    limits: VerticalLimits,
+    costs: ProfileCosts,
+) -> Result<StationRoute, RoutingError> {
+    route_stations_observed(
+        stations,
+        height_map,
+        cost_model,
+        connectivity,
+        limits,
+        costs,
+        &mut NoObserver,
+    )
+}
+
+/// Like `route_stations`, but reports the progress of all the legs together to
+/// `observer`, which may cancel them.
+pub fn route_stations_observed(
+    stations: &[Platform],
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    limits: VerticalLimits,
+    costs: ProfileCosts,
+    observer: &mut impl SearchObserver,
+) -> Result<StationRoute, RoutingError> {
+    if stations.is_empty() {
+        return Err(RoutingError::InvalidSetting {
+            name: "stations",
+            reason: "must have at least one station",
+        });
+    }
+    for station in stations {
+        station.validate(connectivity)?;
+    }
+    let (x_len, y_len) = check_bounds(height_map, &[])?;
+    let as_usize = |(x, y): (i32, i32)| (x as usize, y as usize);
+    let in_bounds =
+        |(x, y): (i32, i32)| x >= 0 && y >= 0 && (x as usize) < x_len && (y as usize) < y_len;
+
+    let mut platform_cells = Vec::new();
+    for station in stations {
+        let cells = station.cells().unwrap_or_default();
+        if cells.len() != station.length || !cells.iter().all(|&cell| in_bounds(cell)) {
+            let point = cells
+                .into_iter()
+                .find(|&cell| !in_bounds(cell))
+                .map_or(station.center, as_usize);
+            return Err(RoutingError::OutOfBounds {
+                point,
+                size: (x_len, y_len),
+            });
+        }
+        platform_cells.push(cells.into_iter().map(as_usize).collect::<Vec<_>>());
+    }
+
+    // Legs between stations may not cross any platform.
+    let mut mask = height_map.raster(false);
+    for &cell in platform_cells.iter().flatten() {
+        mask[cell] = true;
+    }
+    let leg_model = NoGo {
+        inner: cost_model,
+        mask,
+    };
+
+    // Each platform is run forwards (along `direction`) or backwards.
+    let oriented = |station: usize, forwards: bool| {
+        let mut cells = platform_cells[station].clone();
+        if !forwards {
+            cells.reverse();
+        }
+        let (dx, dy) = stations[station].direction;
+        let (dx, dy) = if forwards { (dx, dy) } else { (-dx, -dy) };
+        let (first, last) = (cells[0], cells[cells.len() - 1]);
+        let before = (first.0 as i32 - dx, first.1 as i32 - dy);
+        let after = (last.0 as i32 + dx, last.1 as i32 + dy);
+        (cells, before, after)
+    };
+    let platform_cost = |cells: &[(usize, usize)]| {
+        cells.windows(2).try_fold(0usize, |total, step| {
+            let cost = cost_model.step_cost(height_map, step[0], step[1])?;
+            total.checked_add(cost)
+        })
+    };
+
+    // The cheapest route so far that ends by running through the latest platform
+    // forwards or backwards.
+    let mut stats = SearchStats::default();
+    let mut best: [Option<PartialRoute>; 2] = [true, false].map(|forwards| {
+        let (cells, _, _) = oriented(0, forwards);
+        let cost = platform_cost(&cells)?;
+        let range = 0..cells.len();
+        Some((cells, cost, vec![range]))
+    });
+
+    for station in 1..stations.len() {
+        let mut next: [Option<PartialRoute>; 2] = [None, None];
+        let mut last_error = None;
+        for (slot, forwards) in [true, false].into_iter().enumerate() {
+            let (cells, before, _) = oriented(station, forwards);
+            let Some(cells_cost) = platform_cost(&cells) else {
+                continue;
+            };
+            for (previous_forwards, previous) in [true, false].into_iter().zip(&best) {
+                let Some((path, cost, platforms)) = previous else {
+                    continue;
+                };
+                let (_, _, after) = oriented(station - 1, previous_forwards);
+                if !in_bounds(after) || !in_bounds(before) {
+                    continue;
+                }
+                // Step off the previous platform, route, then step onto this one.
+                let leg = a_star_with_stats(
+                    as_usize(after),
+                    as_usize(before),
+                    height_map,
+                    &leg_model,
+                    connectivity,
+                    &mut stats,
+                    observer,
+                );
+                let leg = match leg {
+                    Ok(leg) => leg,
+                    Err(error @ RoutingError::Cancelled { .. }) => return Err(error),
+                    Err(error) => {
+                        last_error = Some(error);
+                        continue;
+                    }
+                };
+                let steps = [
+                    cost_model.step_cost(height_map, path[path.len() - 1], as_usize(after)),
+                    Some(leg.cost),
+                    cost_model.step_cost(height_map, as_usize(before), cells[0]),
+                    Some(cells_cost),
+                ];
+                let Some(total) = steps
+                    .into_iter()
+                    .try_fold(*cost, |total, step| total.checked_add(step?))
+                else {
+                    continue;
+                };
+                if next[slot]
+                    .as_ref()
+                    .is_some_and(|(_, best, _)| *best <= total)
+                {
+                    continue;
+                }
+
+                let mut path = path.clone();
+                path.extend(leg.path);
+                let start = path.len();
+                path.extend(cells.iter().copied());
+                let mut platforms = platforms.clone();
+                platforms.push(start..path.len());
+                next[slot] = Some((path, total, platforms));
+            }
+        }
+        if next.iter().all(Option::is_none) {
+            return Err(RoutingError::LegFailed {
+                leg: station - 1,
+                error: Box::new(last_error.unwrap_or(RoutingError::NoFeasibleRoute {
+                    start: stations[station - 1].center,
+                    end: stations[station].center,
+                })),
+            });
+        }
+        best = next;
+    }
+
+    let Some((path, cost, platforms)) = best.into_iter().flatten().min_by_key(|(_, cost, _)| *cost)
+    else {
+        return Err(RoutingError::NoFeasibleRoute {
+            start: stations[0].center,
+            end: stations[0].center,
+        });
+    };
+    let profile = optimize_level_profile(&path, height_map, limits, costs, &platforms)?;
+    Ok(StationRoute {
+        route: Route { path, cost },
+        platforms,
+        profile,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        a_star,
+        cost_model::{AbsoluteClimb, FlatDistance},
+        profile::optimize_profile,
+    };
+
+    const LIMITS: VerticalLimits = VerticalLimits {
+        max_grade: 0.5,
+        max_grade_change: 0.25,
+        elevation_step: 0.125,
+    };
+
+    const COSTS: ProfileCosts = ProfileCosts {
+        cut: 1.0,
+        fill: 1.0,
+        max_cut_depth: 3.0,
+        max_fill_height: 3.0,
+        tunnel: None,
+        bridge: None,
+    };
+
+    fn assert_runs_through(route: &StationRoute, stations: &[Platform]) {
+        assert_eq!(route.platforms.len(), stations.len());
+        for (range, station) in route.platforms.iter().zip(stations) {
+            let cells = &route.route.path[range.clone()];
+            assert_eq!(cells.len(), station.length);
+            assert!(cells.contains(&station.center));
+            // The route arrives and leaves along the platform axis.
+            let before = range.start.checked_sub(1).map(|i| route.route.path[i]);
+            let after = route.route.path.get(range.end).copied();
+            let path = before.into_iter().chain(cells.iter().copied()).chain(after);
+            for step in path.collect::<Vec<_>>().windows(2) {
+                let dx = step[1].0 as i32 - step[0].0 as i32;
+                let dy = step[1].1 as i32 - step[0].1 as i32;
+                assert!(
+                    (dx, dy) == station.direction || (-dx, -dy) == station.direction,
+                    "{:?} is off the axis of {:?}",
+                    step,
+                    station
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn arrives_along_the_platform_axis() {
+        let height_map = HeightMap::new(30, 30, vec![0; 30 * 30]);
+        // The second platform runs across the direct line, so the route has to turn to
+        // come in along it.
+        let stations = [
+            Platform {
+                center: (5, 15),
+                direction: (1, 0),
+                length: 5,
+            },
+            Platform {
+                center: (24, 15),
+                direction: (0, 1),
+                length: 4,
+            },
+            Platform {
+                center: (15, 25),
+                direction: (1, 1),
+                length: 3,
+            },
+        ];
+
+        for connectivity in [Connectivity::Eight, Connectivity::AnyAngle] {
+            let route = route_stations(
+                &stations,
+                &height_map,
+                &FlatDistance,
+                connectivity,
+                LIMITS,
+                COSTS,
+            )
+            .unwrap();
+            assert_runs_through(&route, &stations);
+            let cost = route.route.cost;
+            let (direct, _) = a_star(
+                stations[0].center,
+                stations[2].center,
+                &height_map,
+                &FlatDistance,
+                connectivity,
+                |_, _, _| 0,
+            )
+            .unwrap();
+            assert!(cost > direct.cost);
+        }
+
+        assert_eq!(
+            route_stations(
+                &[Platform {
+                    center: (1, 15),
+                    direction: (1, 0),
+                    length: 5,
+                }],
+                &height_map,
+                &FlatDistance,
+                Connectivity::Eight,
+                LIMITS,
+                COSTS,
+            ),
+            Err(RoutingError::OutOfBounds {
+                point: (1, 15),
+                size: (30, 30)
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_platforms() {
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let route = |stations: &[Platform], connectivity| {
+            route_stations(
+                stations,
+                &height_map,
+                &FlatDistance,
+                connectivity,
+                LIMITS,
+                COSTS,
+            )
+        };
+        assert!(matches!(
+            route(&[], Connectivity::Eight),
+            Err(RoutingError::InvalidSetting { .. })
+        ));
+        for (direction, length, connectivity) in [
+            ((1, 0), 0, Connectivity::Eight),
+            ((0, 0), 3, Connectivity::Eight),
+            ((2, 1), 3, Connectivity::Eight),
+            ((1, 1), 3, Connectivity::Four),
+        ] {
+            let platform = Platform {
+                center: (5, 5),
+                direction,
+                length,
+            };
+            assert!(matches!(
+                route(&[platform], connectivity),
+                Err(RoutingError::InvalidSetting { .. })
+            ));
+        }
+        let diagonal = Platform {
+            center: (5, 5),
+            direction: (1, 1),
+            length: 3,
+        };
+        assert!(route(&[diagonal], Connectivity::Eight).is_ok());
+    }
+
+    #[test]
+    fn platforms_are_level() {
+        // A steady slope along x, with platforms running up it.
+        let height_map = HeightMap::from_fn(40, 10, |(x, _)| x as i32 / 4);
+        let stations = [
+            Platform {
+                center: (8, 5),
+                direction: (1, 0),
+                length: 5,
+            },
+            Platform {
+                center: (30, 5),
+                direction: (-1, 0),
+                length: 6,
+            },
+        ];
+
+        let route = route_stations(
+            &stations,
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Eight,
+            LIMITS,
+            COSTS,
+        )
+        .unwrap();
+        assert_runs_through(&route, &stations);
+
+        let profile = &route.profile;
+        assert_eq!(profile.points.len(), route.route.path.len());
+        for range in &route.platforms {
+            let elevation = profile.points[range.start].2;
+            assert!(profile.points[range.clone()]
+                .iter()
+                .all(|&(_, _, z)| z == elevation));
+            // The terrain under the platform slopes, so it is cut or filled level.
+            let cells = &route.route.path[range.clone()];
+            assert!(cells
+                .iter()
+                .any(|&cell| height_map[cell] != height_map[cells[0]]));
+        }
+        // Left to itself, the profile follows the slope across the platforms.
+        let sloped = optimize_profile(&route.route.path, &height_map, LIMITS, COSTS).unwrap();
+        assert!(sloped.cost < profile.cost);
+    }
+}
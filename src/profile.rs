@@ -0,0 +1,388 @@
+// Vertical alignment: choosing the track elevation along a fixed 2D path, so that the track
+// runs at a buildable grade instead of following every bump of the terrain.
+
+use std::{collections::BTreeMap, ops::Range};
+
+use crate::{
+    cost_model::{distance, to_cost},
+    datatypes::HeightMap,
+    structures::TrackKind,
+    RoutingError,
+};
+
+/// How the track elevation may change along the path.
+#[derive(Debug, Clone, Copy)]
+pub struct VerticalLimits {
+    /// The largest height change per cell of horizontal distance.
+    pub max_grade: f64,
+    /// The largest change of grade from one step to the next. This approximates a minimum
+    /// vertical curve radius: a curve of radius `r` changes grade by about `1 / r` per cell.
+    pub max_grade_change: f64,
+    /// Track elevations are chosen from multiples of this, in height units. Grades then
+    /// come in multiples of this per cell, so it should be well below `max_grade_change`.
+    pub elevation_step: f64,
+}
+
+/// What each way of carrying the track past the terrain costs, per cell of track length,
+/// in units of `COST_PER_CELL`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProfileCosts {
+    /// Per unit of depth, where the track runs below the terrain in a cutting.
+    pub cut: f64,
+    /// Per unit of height, where the track runs above the terrain on an embankment.
+    pub fill: f64,
+    /// Cuttings deeper than this must be tunnels instead.
+    pub max_cut_depth: f64,
+    /// Embankments higher than this must be bridges instead.
+    pub max_fill_height: f64,
+    /// `None` if no tunnels may be built.
+    pub tunnel: Option<f64>,
+    /// `None` if no bridges may be built.
+    pub bridge: Option<f64>,
+}
+
+impl ProfileCosts {
+    /// How the track is carried at `elevation` over terrain at `terrain`, and what that
+    /// costs per cell, or `None` if it can't be.
+    fn cell_cost(&self, elevation: f64, terrain: f64) -> Option<(TrackKind, f64)> {
+        let depth = terrain - elevation;
+        if depth > self.max_cut_depth {
+            Some((TrackKind::Tunnel, self.tunnel?))
+        } else if -depth > self.max_fill_height {
+            Some((TrackKind::Bridge, self.bridge?))
+        } else if depth > 0.0 {
+            Some((TrackKind::AtGrade, self.cut * depth))
+        } else {
+            Some((TrackKind::AtGrade, self.fill * -depth))
+        }
+    }
+}
+
+/// A track elevation for every cell of a 2D path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// The 3D polyline of the track: the cells of the path with their track elevations.
+    pub points: Vec<(usize, usize, f64)>,
+    pub kinds: Vec<TrackKind>,
+    pub cost: usize,
+}
+
+/// Finds the cheapest track elevation profile along `path` under `limits`, with both ends
+/// at the terrain height (rounded to `limits.elevation_step`).
+///
+/// This is dynamic programming over every cell, elevation and previous grade, so it is
+/// exact for the discretised elevations. Elevations are kept between the lowest and highest
+/// terrain along the path, and only the elevations from which the end can still be reached
+/// within `limits.max_grade` are kept at each cell.
+///
+/// Returns `RoutingError::NoFeasibleRoute` if no profile meets the limits without
+/// forbidden structures, and `RoutingError::InvalidSetting` if `path` is empty or the
+/// limits aren't positive numbers.
+pub fn optimize_profile(
+    path: &[(usize, usize)],
+    height_map: &HeightMap,
+    limits: VerticalLimits,
+    costs: ProfileCosts,
+) -> Result<Profile, RoutingError> {
+    optimize_level_profile(path, height_map, limits, costs, &[])
+}
+
+/// Like `optimize_profile`, but the track must be level across every range of
+/// `level_ranges`, which index into `path` (such as the platforms of
+/// `platform::StationRoute`).
+pub fn optimize_level_profile(
+    path: &[(usize, usize)],
+    height_map: &HeightMap,
+    limits: VerticalLimits,
+    costs: ProfileCosts,
+    level_ranges: &[Range<usize>],
+) -> Result<Profile, RoutingError> {
+    let (Some(&start), Some(&end)) = (path.first(), path.last()) else {
+        return Err(RoutingError::InvalidSetting {
+            name: "path",
+            reason: "must have at least one cell",
+        });
+    };
+    for (name, value) in [
+        ("max grade", limits.max_grade),
+        ("elevation step", limits.elevation_step),
+    ] {
+        if !value.is_finite() || value <= 0.0 {
+            return Err(RoutingError::InvalidSetting {
+                name,
+                reason: "must be a positive number",
+            });
+        }
+    }
+    if limits.max_grade_change.is_nan() || limits.max_grade_change < 0.0 {
+        return Err(RoutingError::InvalidSetting {
+            name: "max grade change",
+            reason: "must be a non-negative number",
+        });
+    }
+    let terrain = path
+        .iter()
+        .map(|&(x, y)| height_map[(x, y)] as f64)
+        .collect::<Vec<_>>();
+    let steps = path
+        .windows(2)
+        .map(|step| distance(step[0], step[1]))
+        .collect::<Vec<_>>();
+
+    let lowest = terrain.iter().copied().fold(f64::INFINITY, f64::min);
+    let highest = terrain.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let level = |height: f64| ((height - lowest) / limits.elevation_step).round() as usize;
+    let elevation = |level: usize| lowest + level as f64 * limits.elevation_step;
+    let levels = level(highest) + 1;
+
+    // The most levels a step may climb or descend, and the states for every level and
+    // previous step, indexed by `level * climbs + (climb + max_climb)`.
+    let max_climb = steps
+        .iter()
+        .map(|length| (limits.max_grade * length / limits.elevation_step).floor() as usize)
+        .max()
+        .unwrap_or(0);
+    let climbs = 2 * max_climb + 1;
+    let grade = |climb: usize, length: f64| {
+        (climb as f64 - max_climb as f64) * limits.elevation_step / length
+    };
+
+    // The track running past cell `i` costs its cost per cell times half the steps either side.
+    let cell_cost = |i: usize, level: usize| {
+        let length = (i.checked_sub(1).map_or(0.0, |j| steps[j])
+            + steps.get(i).copied().unwrap_or(0.0))
+            / 2.0;
+        costs
+            .cell_cost(elevation(level), terrain[i])
+            .map(|(kind, cost)| (kind, to_cost(cost * length)))
+    };
+
+    // Whether the end can still be reached from `level` at cell `i` without breaking
+    // `max_grade`, which keeps the states near the straight line between the ends.
+    let end_level = level(terrain[path.len() - 1]);
+    let mut remaining = vec![0.0; path.len()];
+    for i in (0..steps.len()).rev() {
+        remaining[i] = remaining[i + 1] + steps[i];
+    }
+    let reaches_end = |i: usize, level: usize| {
+        level.abs_diff(end_level) as f64 * limits.elevation_step
+            <= limits.max_grade * remaining[i] + 1e-9
+    };
+
+    // `states[i]` maps every state reached at cell `i` to the cheapest profile up to there
+    // and the state at cell `i - 1` it came from. Only reached states are stored.
+    let mut states = vec![BTreeMap::new(); path.len()];
+
+    let start_level = level(terrain[0]);
+    if let Some((_, start_cost)) = cell_cost(0, start_level) {
+        states[0].insert(start_level * climbs + max_climb, (start_cost, 0));
+    }
+
+    for i in 0..path.len() - 1 {
+        let length = steps[i];
+        let is_level = level_ranges
+            .iter()
+            .any(|range| range.contains(&i) && range.contains(&(i + 1)));
+        let (reached, rest) = states.split_at_mut(i + 1);
+        let (curr_states, next_states) = (&reached[i], &mut rest[0]);
+        for (&state, &(curr_cost, _)) in curr_states {
+            let (curr_level, prev_climb) = (state / climbs, state % climbs);
+            for climb in 0..climbs {
+                if is_level && climb != max_climb {
+                    continue;
+                }
+                let next_grade = grade(climb, length);
+                if next_grade.abs() > limits.max_grade + 1e-9 {
+                    continue;
+                }
+                if i > 0
+                    && (next_grade - grade(prev_climb, steps[i - 1])).abs()
+                        > limits.max_grade_change + 1e-9
+                {
+                    continue;
+                }
+                let Some(next_level) = (curr_level + climb)
+                    .checked_sub(max_climb)
+                    .filter(|&level| level < levels && reaches_end(i + 1, level))
+                else {
+                    continue;
+                };
+                let Some((_, next_cell_cost)) = cell_cost(i + 1, next_level) else {
+                    continue;
+                };
+                let next_state = next_level * climbs + climb;
+                let next_cost = curr_cost.saturating_add(next_cell_cost);
+                if next_states
+                    .get(&next_state)
+                    .is_none_or(|&(best, _)| next_cost < best)
+                {
+                    next_states.insert(next_state, (next_cost, state));
+                }
+            }
+        }
+    }
+
+    let Some((mut state, total)) = (0..climbs)
+        .map(|climb| end_level * climbs + climb)
+        .filter_map(|state| Some((state, states[path.len() - 1].get(&state)?.0)))
+        .min_by_key(|&(_, cost)| cost)
+    else {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    };
+
+    let mut levels_along = vec![0; path.len()];
+    for i in (0..path.len()).rev() {
+        levels_along[i] = state / climbs;
+        state = states[i][&state].1;
+    }
+
+    let (points, kinds) = path
+        .iter()
+        .zip(levels_along)
+        .enumerate()
+        .map(|(i, (&(x, y), level))| {
+            let (kind, _) = cell_cost(i, level).unwrap();
+            ((x, y, elevation(level)), kind)
+        })
+        .unzip();
+    Ok(Profile {
+        points,
+        kinds,
+        cost: total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: VerticalLimits = VerticalLimits {
+        max_grade: 0.5,
+        max_grade_change: 0.25,
+        elevation_step: 0.125,
+    };
+
+    const COSTS: ProfileCosts = ProfileCosts {
+        cut: 1.0,
+        fill: 1.0,
+        max_cut_depth: 3.0,
+        max_fill_height: 3.0,
+        tunnel: Some(20.0),
+        bridge: Some(15.0),
+    };
+
+    fn assert_within_limits(profile: &Profile, limits: VerticalLimits) {
+        let grades = profile
+            .points
+            .windows(2)
+            .map(|step| {
+                let length = distance((step[0].0, step[0].1), (step[1].0, step[1].1));
+                (step[1].2 - step[0].2) / length
+            })
+            .collect::<Vec<_>>();
+        assert!(grades
+            .iter()
+            .all(|grade| grade.abs() <= limits.max_grade + 1e-9));
+        for pair in grades.windows(2) {
+            assert!((pair[1] - pair[0]).abs() <= limits.max_grade_change + 1e-9);
+        }
+    }
+
+    #[test]
+    fn flat_terrain_stays_flat() {
+        let height_map = HeightMap::new(10, 10, vec![4; 10 * 10]);
+        let path = (0..10).map(|x| (x, x)).collect::<Vec<_>>();
+
+        let profile = optimize_profile(&path, &height_map, LIMITS, COSTS).unwrap();
+        assert!(profile.points.iter().all(|&(_, _, z)| z == 4.0));
+        assert!(profile.kinds.iter().all(|&kind| kind == TrackKind::AtGrade));
+        assert_eq!(profile.cost, 0);
+    }
+
+    #[test]
+    fn smooths_a_bumpy_slope() {
+        // A steady climb with a bump every few cells.
+        let height_map = HeightMap::from_fn(20, 1, |(x, _)| {
+            x as i32 / 3 + if x % 4 == 2 { 2 } else { 0 }
+        });
+        let path = (0..20).map(|x| (x, 0)).collect::<Vec<_>>();
+
+        let profile = optimize_profile(&path, &height_map, LIMITS, COSTS).unwrap();
+        assert_within_limits(&profile, LIMITS);
+        assert_eq!(profile.points[0].2, 0.0);
+        assert_eq!(profile.points[19].2, 6.0);
+        // Following the terrain exactly would break the grade limits, so the track cuts
+        // through the bumps instead.
+        assert!(profile.cost > 0);
+        assert!(profile.kinds.iter().all(|&kind| kind == TrackKind::AtGrade));
+    }
+
+    #[test]
+    fn tunnels_and_bridges() {
+        // A mountain then a gorge along the path.
+        let mut heights = vec![10; 30];
+        heights[8..14].fill(30);
+        heights[18..23].fill(-10);
+        let height_map = HeightMap::new(30, 1, heights);
+        let path = (0..30).map(|x| (x, 0)).collect::<Vec<_>>();
+
+        let profile = optimize_profile(&path, &height_map, LIMITS, COSTS).unwrap();
+        assert_within_limits(&profile, LIMITS);
+        assert!(profile.kinds[8..14]
+            .iter()
+            .all(|&kind| kind == TrackKind::Tunnel));
+        assert!(profile.kinds[18..23]
+            .iter()
+            .all(|&kind| kind == TrackKind::Bridge));
+
+        let no_structures = ProfileCosts {
+            tunnel: None,
+            bridge: None,
+            ..COSTS
+        };
+        assert_eq!(
+            optimize_profile(&path, &height_map, LIMITS, no_structures),
+            Err(RoutingError::NoFeasibleRoute {
+                start: (0, 0),
+                end: (29, 0)
+            })
+        );
+    }
+
+    #[test]
+    fn only_reachable_elevations_are_stored() {
+        // One very tall spike: a table over every elevation up to its top would have
+        // over a hundred million states.
+        let mut heights = vec![0; 100];
+        heights[50] = 10_000;
+        let height_map = HeightMap::new(100, 1, heights);
+        let path = (0..100).map(|x| (x, 0)).collect::<Vec<_>>();
+
+        let profile = optimize_profile(&path, &height_map, LIMITS, COSTS).unwrap();
+        assert_within_limits(&profile, LIMITS);
+        assert_eq!(profile.kinds[50], TrackKind::Tunnel);
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        let height_map = HeightMap::new(10, 1, vec![0; 10]);
+        let path = (0..10).map(|x| (x, 0)).collect::<Vec<_>>();
+        assert!(matches!(
+            optimize_profile(&[], &height_map, LIMITS, COSTS),
+            Err(RoutingError::InvalidSetting { name: "path", .. })
+        ));
+        for elevation_step in [0.0, -0.5, f64::NAN] {
+            let limits = VerticalLimits {
+                elevation_step,
+                ..LIMITS
+            };
+            assert!(matches!(
+                optimize_profile(&path, &height_map, limits, COSTS),
+                Err(RoutingError::InvalidSetting {
+                    name: "elevation step",
+                    ..
+                })
+            ));
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// Feedback from long searches, and a way to stop them early.
+
+use std::ops::ControlFlow;
+
+use crate::RoutingError;
+
+/// How far a search has got, as handed to a `SearchObserver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of positions expanded so far.
+    pub expanded: usize,
+    /// Number of entries waiting on the frontier, including stale ones.
+    pub frontier: usize,
+    /// The cost so far of the position that was just expanded. Positions are expanded in
+    /// order of `best_cost`, so only for Dijkstra has everything cheaper than this already
+    /// been expanded.
+    pub cost: usize,
+    /// `cost` plus the heuristic estimate of the remaining cost, which is the least the
+    /// route can still cost. For Dijkstra this is the same as `cost`.
+    pub best_cost: usize,
+}
+
+/// Watches a search as it runs, and may cancel it.
+///
+/// A cancelled search fails with `RoutingError::Cancelled`, which carries the last
+/// `Progress`.
+pub trait SearchObserver {
+    /// Called after every `interval()` expansions.
+    fn progress(&mut self, progress: &Progress) -> ControlFlow<()>;
+
+    /// How many expansions to wait between calls to `progress`.
+    fn interval(&self) -> usize {
+        1000
+    }
+}
+
+/// Hands `progress` to `observer` if `progress.expanded` is a multiple of its interval,
+/// and turns a cancellation into `RoutingError::Cancelled`.
+pub(crate) fn report(
+    observer: &mut impl SearchObserver,
+    progress: Progress,
+) -> Result<(), RoutingError> {
+    if progress.expanded.is_multiple_of(observer.interval().max(1))
+        && observer.progress(&progress).is_break()
+    {
+        return Err(RoutingError::Cancelled { progress });
+    }
+    Ok(())
+}
+
+/// Closures can observe searches too, at the default interval.
+impl<F: FnMut(&Progress) -> ControlFlow<()>> SearchObserver for F {
+    fn progress(&mut self, progress: &Progress) -> ControlFlow<()> {
+        self(progress)
+    }
+}
+
+/// Lets searches run to the end without reporting anything.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoObserver;
+
+impl SearchObserver for NoObserver {
+    fn progress(&mut self, _progress: &Progress) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    fn interval(&self) -> usize {
+        usize::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        a_star_observed,
+        cost_model::{AbsoluteClimb, CostModel},
+        datatypes::HeightMap,
+        dijkstra, dijkstra_observed, Connectivity, RoutingError,
+    };
+
+    /// Keeps every progress report, and cancels once `limit` positions are expanded.
+    struct Recorder {
+        reports: Vec<Progress>,
+        limit: usize,
+    }
+
+    impl SearchObserver for Recorder {
+        fn progress(&mut self, progress: &Progress) -> ControlFlow<()> {
+            self.reports.push(*progress);
+            if progress.expanded >= self.limit {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+
+        fn interval(&self) -> usize {
+            100
+        }
+    }
+
+    fn height_map() -> HeightMap {
+        HeightMap::from_fn(60, 60, |(x, y)| (x * y % 7) as i32)
+    }
+
+    #[test]
+    fn reports_progress_without_changing_the_route() {
+        let height_map = height_map();
+        let cost_model = AbsoluteClimb::default();
+        let mut recorder = Recorder {
+            reports: Vec::new(),
+            limit: usize::MAX,
+        };
+
+        let route = dijkstra_observed(
+            (0, 0),
+            (59, 59),
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            &mut recorder,
+        )
+        .unwrap();
+        assert_eq!(
+            route,
+            dijkstra(
+                (0, 0),
+                (59, 59),
+                &height_map,
+                &cost_model,
+                Connectivity::Eight
+            )
+            .unwrap()
+        );
+
+        assert!(!recorder.reports.is_empty());
+        for (i, progress) in recorder.reports.iter().enumerate() {
+            assert_eq!(progress.expanded, (i + 1) * 100);
+            assert_eq!(progress.cost, progress.best_cost);
+            assert!(progress.cost <= route.cost);
+        }
+        // Dijkstra expands positions in order of cost.
+        assert!(recorder
+            .reports
+            .windows(2)
+            .all(|pair| pair[0].cost <= pair[1].cost));
+    }
+
+    #[test]
+    fn cancels_with_the_last_progress() {
+        let height_map = height_map();
+        let cost_model = AbsoluteClimb::default();
+        let mut recorder = Recorder {
+            reports: Vec::new(),
+            limit: 300,
+        };
+
+        let result = a_star_observed(
+            (0, 0),
+            (59, 59),
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            |from, to, height_map| {
+                cost_model.lower_bound(height_map, from, to, Connectivity::Eight)
+            },
+            &mut recorder,
+        );
+        let Err(RoutingError::Cancelled { progress }) = result else {
+            panic!("expected the search to be cancelled, got {:?}", result);
+        };
+        assert_eq!(progress.expanded, 300);
+        assert_eq!(recorder.reports.last(), Some(&progress));
+        assert!(progress.best_cost >= progress.cost);
+
+        // Closures work as observers too.
+        let mut calls = 0;
+        let result = dijkstra_observed(
+            (0, 0),
+            (59, 59),
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            &mut |_: &Progress| {
+                calls += 1;
+                ControlFlow::Break(())
+            },
+        );
+        assert!(matches!(result, Err(RoutingError::Cancelled { .. })));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,233 @@
+// One-to-many routing: a single search from a source that answers for many targets.
+
+use std::collections::HashSet;
+
+use crate::{
+    check_bounds,
+    cost_model::CostModel,
+    datatypes::HeightMap,
+    expand_cell, grow_search_tree,
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Route, RoutingError, SearchStats, SearchTree,
+};
+
+/// The cheapest paths from one source cell to every cell a search settled.
+pub struct ShortestPathTree {
+    tree: SearchTree<(usize, usize)>,
+}
+
+impl ShortestPathTree {
+    pub fn source(&self) -> (usize, usize) {
+        self.tree.start
+    }
+
+    /// The cost of the cheapest route from the source to `cell`, or `None` if the search
+    /// didn't reach it (it is unreachable, or the search stopped before getting there).
+    pub fn cost_to(&self, cell: (usize, usize)) -> Option<usize> {
+        self.tree.cost(cell)
+    }
+
+    /// The cheapest route from the source to `cell`, see `cost_to`.
+    pub fn route_to(&self, cell: (usize, usize)) -> Option<Route> {
+        Some(Route {
+            path: self.tree.path(cell)?,
+            cost: self.tree.cost(cell)?,
+        })
+    }
+}
+
+/// Runs a single Dijkstra search from `source`, which stops once every cell in `targets`
+/// is settled, or covers the whole map if `targets` is `None`.
+///
+/// Unreachable targets aren't an error, they are just missing from the tree.
+pub fn shortest_path_tree(
+    source: (usize, usize),
+    targets: Option<&[(usize, usize)]>,
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+) -> Result<ShortestPathTree, RoutingError> {
+    shortest_path_tree_observed(
+        source,
+        targets,
+        height_map,
+        cost_model,
+        connectivity,
+        &mut NoObserver,
+    )
+}
+
+/// Like `shortest_path_tree`, but reports its progress to `observer`, which may cancel
+/// the search.
+pub fn shortest_path_tree_observed(
+    source: (usize, usize),
+    targets: Option<&[(usize, usize)]>,
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    observer: &mut impl SearchObserver,
+) -> Result<ShortestPathTree, RoutingError> {
+    grow_tree(
+        source,
+        targets,
+        height_map,
+        cost_model,
+        connectivity,
+        &mut SearchStats::default(),
+        observer,
+    )
+}
+
+/// `shortest_path_tree_observed`, counting the expanded cells in `stats` so that several
+/// searches can report their progress together.
+fn grow_tree(
+    source: (usize, usize),
+    targets: Option<&[(usize, usize)]>,
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    stats: &mut SearchStats,
+    observer: &mut impl SearchObserver,
+) -> Result<ShortestPathTree, RoutingError> {
+    check_bounds(height_map, &[source])?;
+    check_bounds(height_map, targets.unwrap_or_default())?;
+
+    let mut remaining = targets.map(|targets| targets.iter().copied().collect::<HashSet<_>>());
+
+    let tree = grow_search_tree(
+        source,
+        |position| match remaining.as_mut() {
+            Some(remaining) => {
+                remaining.remove(&position);
+                remaining.is_empty()
+            }
+            None => false,
+        },
+        |_| 0,
+        |curr, relaxations| expand_cell(curr, relaxations, height_map, cost_model, connectivity),
+        connectivity.can_reopen(),
+        stats,
+        observer,
+    )?;
+
+    Ok(ShortestPathTree { tree })
+}
+
+/// The cost of the cheapest route between every pair of `stations`, with one search
+/// per station. `costs[i][j]` is `None` if station `j` can't be reached from station `i`.
+pub fn corridor_costs(
+    stations: &[(usize, usize)],
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+) -> Result<Vec<Vec<Option<usize>>>, RoutingError> {
+    corridor_costs_observed(
+        stations,
+        height_map,
+        cost_model,
+        connectivity,
+        &mut NoObserver,
+    )
+}
+
+/// Like `corridor_costs`, but reports the progress of all the searches together to
+/// `observer`, which may cancel them.
+pub fn corridor_costs_observed(
+    stations: &[(usize, usize)],
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    observer: &mut impl SearchObserver,
+) -> Result<Vec<Vec<Option<usize>>>, RoutingError> {
+    let mut stats = SearchStats::default();
+    stations
+        .iter()
+        .map(|&station| {
+            let tree = grow_tree(
+                station,
+                Some(stations),
+                height_map,
+                cost_model,
+                connectivity,
+                &mut stats,
+                observer,
+            )?;
+            Ok(stations.iter().map(|&other| tree.cost_to(other)).collect())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cost_model::{AbsoluteClimb, NoGo},
+        datatypes::Raster,
+        dijkstra,
+    };
+
+    fn bumpy_height_map() -> HeightMap {
+        HeightMap::from_fn(30, 30, |(x, y)| (x as i32 * 7 + y as i32 * 13) % 11)
+    }
+
+    #[test]
+    fn tree_matches_dijkstra() {
+        let height_map = bumpy_height_map();
+        let cost_model = AbsoluteClimb::default();
+        let source = (3, 4);
+        let targets = [(0, 0), (29, 29), (15, 2), (3, 4)];
+
+        for targets in [Some(&targets[..]), None] {
+            let tree = shortest_path_tree(
+                source,
+                targets,
+                &height_map,
+                &cost_model,
+                Connectivity::Eight,
+            )
+            .unwrap();
+            assert_eq!(tree.source(), source);
+            for target in [(0, 0), (29, 29), (15, 2), (3, 4)] {
+                let expected = dijkstra(
+                    source,
+                    target,
+                    &height_map,
+                    &cost_model,
+                    Connectivity::Eight,
+                )
+                .unwrap();
+                let route = tree.route_to(target).unwrap();
+                assert_eq!(route.cost, expected.cost);
+                assert_eq!(route.path.first(), Some(&source));
+                assert_eq!(route.path.last(), Some(&target));
+            }
+        }
+    }
+
+    #[test]
+    fn all_pairs_corridor_costs() {
+        let height_map = bumpy_height_map();
+        // Wall off the bottom right corner.
+        let mask = Raster::from_fn(30, 30, |(x, y)| {
+            (x == 25 && y >= 25) || (x >= 25 && y == 25)
+        });
+        let cost_model = NoGo {
+            inner: AbsoluteClimb::default(),
+            mask,
+        };
+        let stations = [(0, 0), (10, 20), (20, 5), (28, 28)];
+
+        let costs =
+            corridor_costs(&stations, &height_map, &cost_model, Connectivity::Eight).unwrap();
+        for (i, &from) in stations.iter().enumerate() {
+            for (j, &to) in stations.iter().enumerate() {
+                let expected = dijkstra(from, to, &height_map, &cost_model, Connectivity::Eight)
+                    .ok()
+                    .map(|route| route.cost);
+                assert_eq!(costs[i][j], expected, "from {:?} to {:?}", from, to);
+            }
+        }
+        assert_eq!(costs[0][3], None);
+        assert_eq!(costs[3][3], Some(0));
+    }
+}
@@ -0,0 +1,253 @@
+// Turning dense cell paths into the straight-line segments that `main` describes station
+// paths with.
+
+use crate::{cost_model::distance, datatypes::HeightMap, RoutingError};
+
+/// How far a simplified polyline may stray from the path it replaces.
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerance {
+    /// The furthest any cell of the original path may be from the polyline, in cells.
+    pub horizontal: f64,
+    /// The furthest the terrain under any cell of the original path may be from the
+    /// polyline's height there, in height units. The polyline's height runs in a straight
+    /// line between the terrain heights at its vertices.
+    pub vertical: f64,
+}
+
+/// Simplifies `path` with Douglas–Peucker: returns a subset of its points, including both
+/// ends, such that every point in between stays within `tolerance` of the segment that
+/// replaces it.
+///
+/// A tolerance of 0 only drops points that lie exactly on the segment. Negative or NaN
+/// tolerances are rejected with `RoutingError::InvalidSetting`.
+pub fn simplify_path(
+    path: &[(usize, usize)],
+    height_map: &HeightMap,
+    tolerance: Tolerance,
+) -> Result<Vec<(usize, usize)>, RoutingError> {
+    for (name, value) in [
+        ("horizontal tolerance", tolerance.horizontal),
+        ("vertical tolerance", tolerance.vertical),
+    ] {
+        if value.is_nan() || value < 0.0 {
+            return Err(RoutingError::InvalidSetting {
+                name,
+                reason: "must be a non-negative number",
+            });
+        }
+    }
+    if path.len() < 3 {
+        return Ok(path.to_vec());
+    }
+
+    let mut keep = vec![false; path.len()];
+    keep[0] = true;
+    keep[path.len() - 1] = true;
+
+    // Segments still to check, as indices into `path`.
+    let mut segments = vec![(0, path.len() - 1)];
+    while let Some((first, last)) = segments.pop() {
+        // The point that strays the furthest beyond the tolerance.
+        let furthest = (first + 1..last)
+            .map(|i| {
+                let (horizontal, vertical) =
+                    deviation(path[i], path[first], path[last], height_map);
+                let excess = (horizontal - tolerance.horizontal).max(vertical - tolerance.vertical);
+                (i, excess)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        if let Some((i, excess)) = furthest {
+            if excess > 0.0 {
+                keep[i] = true;
+                segments.push((first, i));
+                segments.push((i, last));
+            }
+        }
+    }
+
+    Ok(path
+        .iter()
+        .zip(keep)
+        .filter_map(|(&point, keep)| keep.then_some(point))
+        .collect())
+}
+
+/// How far `point` is from the segment from `from` to `to`: horizontally, and between the
+/// terrain under it and the segment's interpolated height.
+fn deviation(
+    point: (usize, usize),
+    from: (usize, usize),
+    to: (usize, usize),
+    height_map: &HeightMap,
+) -> (f64, f64) {
+    let (px, py) = (point.0 as f64, point.1 as f64);
+    let (ax, ay) = (from.0 as f64, from.1 as f64);
+    let (dx, dy) = (to.0 as f64 - ax, to.1 as f64 - ay);
+    let length_squared = dx * dx + dy * dy;
+
+    // How far along the segment the closest point to `point` is, from 0 to 1.
+    let t = if length_squared == 0.0 {
+        0.0
+    } else {
+        (((px - ax) * dx + (py - ay) * dy) / length_squared).clamp(0.0, 1.0)
+    };
+    let horizontal = (px - (ax + t * dx)).hypot(py - (ay + t * dy));
+
+    let height = |(x, y): (usize, usize)| height_map[(x, y)] as f64;
+    let interpolated = height(from) + t * (height(to) - height(from));
+    (horizontal, (height(point) - interpolated).abs())
+}
+
+/// The straight lines between consecutive points of `polyline`, in the form of the
+/// station paths in `main`'s `transit_line_paths`.
+pub fn to_segments(polyline: &[(usize, usize)]) -> Vec<((usize, usize), (usize, usize))> {
+    polyline
+        .windows(2)
+        .map(|segment| (segment[0], segment[1]))
+        .collect()
+}
+
+/// The horizontal length of `polyline`, in cells.
+pub fn polyline_length(polyline: &[(usize, usize)]) -> f64 {
+    polyline
+        .windows(2)
+        .map(|segment| distance(segment[0], segment[1]))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cost_model::AbsoluteClimb, dijkstra, Connectivity};
+
+    const TOLERANCE: Tolerance = Tolerance {
+        horizontal: 1.0,
+        vertical: 2.0,
+    };
+
+    fn assert_within_tolerance(
+        path: &[(usize, usize)],
+        polyline: &[(usize, usize)],
+        height_map: &HeightMap,
+        tolerance: Tolerance,
+    ) {
+        assert_eq!(path.first(), polyline.first());
+        assert_eq!(path.last(), polyline.last());
+        // Every point of the path lies between two consecutive vertices of the polyline.
+        let mut vertices = polyline.iter().map(|vertex| {
+            path.iter()
+                .position(|point| point == vertex)
+                .expect("vertices come from the path")
+        });
+        let mut first = vertices.next().unwrap();
+        for last in vertices {
+            for &point in &path[first..=last] {
+                let (horizontal, vertical) = deviation(point, path[first], path[last], height_map);
+                assert!(horizontal <= tolerance.horizontal, "{:?} strays", point);
+                assert!(vertical <= tolerance.vertical, "{:?} strays", point);
+            }
+            first = last;
+        }
+    }
+
+    #[test]
+    fn straight_lines_and_corners() {
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let straight = (0..10).map(|x| (x, 3)).collect::<Vec<_>>();
+        assert_eq!(
+            simplify_path(&straight, &height_map, TOLERANCE).unwrap(),
+            vec![(0, 3), (9, 3)]
+        );
+
+        let corner = (0..10)
+            .map(|x| (x, 0))
+            .chain((1..10).map(|y| (9, y)))
+            .collect::<Vec<_>>();
+        let polyline = simplify_path(&corner, &height_map, TOLERANCE).unwrap();
+        assert_eq!(polyline, vec![(0, 0), (9, 0), (9, 9)]);
+        assert_eq!(
+            to_segments(&polyline),
+            vec![((0, 0), (9, 0)), ((9, 0), (9, 9))]
+        );
+        assert_eq!(polyline_length(&polyline), 18.0);
+    }
+
+    #[test]
+    fn keeps_vertices_over_terrain_bumps() {
+        // A hill peaking at x = 5.
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| 5 - (x as i32 - 5).abs());
+        let path = (0..10).map(|x| (x, 0)).collect::<Vec<_>>();
+
+        assert_eq!(
+            simplify_path(&path, &height_map, TOLERANCE).unwrap(),
+            vec![(0, 0), (5, 0), (9, 0)]
+        );
+        let lenient = Tolerance {
+            horizontal: 1.0,
+            vertical: 5.0,
+        };
+        assert_eq!(
+            simplify_path(&path, &height_map, lenient).unwrap(),
+            vec![(0, 0), (9, 0)]
+        );
+    }
+
+    #[test]
+    fn zero_tolerance_keeps_every_bend() {
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| 5 - (x as i32 - 5).abs());
+        let path = (0..10).map(|x| (x, 0)).collect::<Vec<_>>();
+        let exact = Tolerance {
+            horizontal: 0.0,
+            vertical: 0.0,
+        };
+        assert_eq!(
+            simplify_path(&path, &height_map, exact).unwrap(),
+            vec![(0, 0), (5, 0), (9, 0)]
+        );
+
+        let staircase = [(0, 0), (1, 0), (1, 1), (2, 1), (2, 2)];
+        let flat = HeightMap::new(3, 3, vec![0; 3 * 3]);
+        assert_eq!(simplify_path(&staircase, &flat, exact).unwrap(), staircase);
+    }
+
+    #[test]
+    fn rejects_invalid_tolerances() {
+        let height_map = HeightMap::new(10, 10, vec![0; 10 * 10]);
+        let path = (0..10).map(|x| (x, 0)).collect::<Vec<_>>();
+        for tolerance in [
+            Tolerance {
+                horizontal: -1.0,
+                vertical: 2.0,
+            },
+            Tolerance {
+                horizontal: 1.0,
+                vertical: f64::NAN,
+            },
+        ] {
+            assert!(matches!(
+                simplify_path(&path, &height_map, tolerance),
+                Err(RoutingError::InvalidSetting { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn simplifies_routed_paths() {
+        let height_map = HeightMap::from_fn(60, 60, |(x, y)| {
+            ((x as f64 / 6.0).sin() * 3.0) as i32 + y as i32 / 10
+        });
+        let route = dijkstra(
+            (0, 0),
+            (59, 45),
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Eight,
+        )
+        .unwrap();
+
+        let polyline = simplify_path(&route.path, &height_map, TOLERANCE).unwrap();
+        assert!(polyline.len() < route.path.len() / 4, "{:?}", polyline);
+        assert_within_tolerance(&route.path, &polyline, &height_map, TOLERANCE);
+    }
+}
@@ -0,0 +1,399 @@
+// Routing that may leave the terrain surface, boring tunnels below it or building bridges above it.
+
+use crate::{
+    best_first_search, check_bounds,
+    cost_model::{distance, to_cost, CostModel},
+    datatypes::HeightMap,
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Relaxation, RoutingError, SearchStats,
+};
+
+/// How the track is carried through a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrackKind {
+    /// Laid on the terrain surface.
+    AtGrade,
+    /// Below the terrain surface.
+    Tunnel,
+    /// Above the terrain surface.
+    Bridge,
+}
+
+/// What one kind of structure costs to build, and how long it may be.
+#[derive(Debug, Clone, Copy)]
+pub struct StructureCosts {
+    /// Charged per cell of horizontal distance inside the structure, in units of `COST_PER_CELL`.
+    pub cell_cost: f64,
+    /// Charged for each portal (tunnels) or abutment (bridges), i.e. twice per structure,
+    /// in units of `COST_PER_CELL`.
+    pub portal_cost: f64,
+    /// The most cells a single structure may span.
+    pub max_length: usize,
+}
+
+/// Which structures the router may build.
+#[derive(Debug, Clone, Copy)]
+pub struct Structures {
+    /// `None` if no tunnels may be built.
+    pub tunnel: Option<StructureCosts>,
+    /// `None` if no bridges may be built.
+    pub bridge: Option<StructureCosts>,
+    /// How much the track elevation may change per step inside a structure, and between
+    /// a structure and the terrain at its ends.
+    pub max_climb: i32,
+}
+
+impl Structures {
+    fn costs(&self, kind: TrackKind) -> Option<StructureCosts> {
+        match kind {
+            TrackKind::AtGrade => None,
+            TrackKind::Tunnel => self.tunnel,
+            TrackKind::Bridge => self.bridge,
+        }
+    }
+}
+
+/// One cell of a `TrackRoute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrackCell {
+    pub position: (usize, usize),
+    pub kind: TrackKind,
+    /// The height the track runs at. For `TrackKind::AtGrade` this is the terrain height,
+    /// so the track's own voxel sits on top, at `elevation + 1`.
+    pub elevation: i32,
+}
+
+/// A route that may run through tunnels and over bridges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackRoute {
+    /// Every cell on the path, from the start to the end inclusive.
+    pub cells: Vec<TrackCell>,
+    pub cost: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct TrackState {
+    position: (usize, usize),
+    kind: TrackKind,
+    elevation: i32,
+    /// Cells spent inside the current structure, 0 at grade.
+    length: usize,
+}
+
+/// Searches for the cheapest route from `start` to `end`, which may tunnel below or bridge
+/// over the terrain as allowed by `structures`. Both ends are at grade.
+///
+/// Steps at grade are charged by `cost_model` as usual, so combining this with
+/// `MaxGrade` and `SteepSteps::Forbid` forces steep sections into structures.
+/// Structures aren't charged by `cost_model`, but only run through cells that
+/// `CostModel::allows_cell` accepts, so they don't cross `NoGo` cells either.
+/// `Connectivity::AnyAngle` is treated like `Connectivity::Eight`.
+///
+/// There is no heuristic, since structures can undercut any lower bound the cost model gives.
+pub fn route_with_structures(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    structures: Structures,
+) -> Result<(TrackRoute, SearchStats), RoutingError> {
+    route_with_structures_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        structures,
+        &mut NoObserver,
+    )
+}
+
+/// Like `route_with_structures`, but reports its progress to `observer`, which may cancel
+/// the search. Every expanded state counts as an expanded position.
+pub fn route_with_structures_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    structures: Structures,
+    observer: &mut impl SearchObserver,
+) -> Result<(TrackRoute, SearchStats), RoutingError> {
+    check_bounds(height_map, &[start, end])?;
+    let terrain = |position| height_map[position];
+
+    let at_grade = |position: (usize, usize)| TrackState {
+        position,
+        kind: TrackKind::AtGrade,
+        elevation: terrain(position),
+        length: 0,
+    };
+
+    let mut stats = SearchStats::default();
+    let result = best_first_search(
+        at_grade(start),
+        |state| state.position == end && state.kind == TrackKind::AtGrade,
+        |_| 0,
+        |curr, relaxations| {
+            let curr = curr.position;
+            for neighbor in height_map.neighbors(curr.position, connectivity) {
+                if !cost_model.allows_cell(neighbor) {
+                    continue;
+                }
+                let length = distance(curr.position, neighbor);
+                // Every elevation the track may take in `neighbor` while inside a structure.
+                let elevations =
+                    curr.elevation - structures.max_climb..=curr.elevation + structures.max_climb;
+
+                match structures.costs(curr.kind) {
+                    None => {
+                        if let Some(step_cost) =
+                            cost_model.step_cost(height_map, curr.position, neighbor)
+                        {
+                            relaxations.push(Relaxation {
+                                position: at_grade(neighbor),
+                                from: curr,
+                                step_cost,
+                            });
+                        }
+                        // Enter a structure.
+                        for (kind, costs) in [
+                            (TrackKind::Tunnel, structures.tunnel),
+                            (TrackKind::Bridge, structures.bridge),
+                        ] {
+                            let Some(costs) = costs else {
+                                continue;
+                            };
+                            if costs.max_length == 0 {
+                                continue;
+                            }
+                            for elevation in elevations.clone() {
+                                if !clears_terrain(kind, elevation, terrain(neighbor)) {
+                                    continue;
+                                }
+                                relaxations.push(Relaxation {
+                                    position: TrackState {
+                                        position: neighbor,
+                                        kind,
+                                        elevation,
+                                        length: 1,
+                                    },
+                                    from: curr,
+                                    step_cost: to_cost(
+                                        costs.portal_cost + costs.cell_cost * length,
+                                    ),
+                                });
+                            }
+                        }
+                    }
+                    Some(costs) => {
+                        // Carry on inside the structure.
+                        if curr.length < costs.max_length {
+                            for elevation in elevations {
+                                if !clears_terrain(curr.kind, elevation, terrain(neighbor)) {
+                                    continue;
+                                }
+                                relaxations.push(Relaxation {
+                                    position: TrackState {
+                                        position: neighbor,
+                                        kind: curr.kind,
+                                        elevation,
+                                        length: curr.length + 1,
+                                    },
+                                    from: curr,
+                                    step_cost: to_cost(costs.cell_cost * length),
+                                });
+                            }
+                        }
+                        // Come back to the surface.
+                        if (terrain(neighbor) - curr.elevation).abs() <= structures.max_climb {
+                            relaxations.push(Relaxation {
+                                position: at_grade(neighbor),
+                                from: curr,
+                                step_cost: to_cost(costs.portal_cost + costs.cell_cost * length),
+                            });
+                        }
+                    }
+                }
+            }
+        },
+        true,
+        &mut stats,
+        observer,
+    )?;
+    let Some((path, cost)) = result else {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    };
+
+    Ok((
+        TrackRoute {
+            cells: path
+                .into_iter()
+                .map(|state| TrackCell {
+                    position: state.position,
+                    kind: state.kind,
+                    elevation: state.elevation,
+                })
+                .collect(),
+            cost,
+        },
+        stats,
+    ))
+}
+
+/// Whether a structure of `kind` at `elevation` stays clear of terrain at `terrain_height`.
+fn clears_terrain(kind: TrackKind, elevation: i32, terrain_height: i32) -> bool {
+    match kind {
+        TrackKind::AtGrade => elevation == terrain_height,
+        TrackKind::Tunnel => elevation < terrain_height,
+        TrackKind::Bridge => elevation > terrain_height,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cost_model::{AbsoluteClimb, MaxGrade, NoGo, SteepSteps, COST_PER_CELL},
+        datatypes::Raster,
+    };
+
+    const STRUCTURES: Structures = Structures {
+        tunnel: Some(StructureCosts {
+            cell_cost: 3.0,
+            portal_cost: 5.0,
+            max_length: 4,
+        }),
+        bridge: Some(StructureCosts {
+            cell_cost: 2.0,
+            portal_cost: 4.0,
+            max_length: 4,
+        }),
+        max_climb: 1,
+    };
+
+    fn assert_consistent(route: &TrackRoute, height_map: &HeightMap) {
+        for cell in &route.cells {
+            let terrain = height_map[cell.position];
+            assert!(
+                clears_terrain(cell.kind, cell.elevation, terrain),
+                "{:?} over terrain at {}",
+                cell,
+                terrain
+            );
+        }
+        assert_eq!(route.cells.first().unwrap().kind, TrackKind::AtGrade);
+        assert_eq!(route.cells.last().unwrap().kind, TrackKind::AtGrade);
+    }
+
+    #[test]
+    fn tunnels_through_a_ridge() {
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| if x == 4 || x == 5 { 50 } else { 0 });
+
+        let (route, _) = route_with_structures(
+            (0, 0),
+            (9, 0),
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Four,
+            STRUCTURES,
+        )
+        .unwrap();
+
+        assert_consistent(&route, &height_map);
+        let kinds = route.cells.iter().map(|cell| cell.kind).collect::<Vec<_>>();
+        assert_eq!(kinds[4..6], [TrackKind::Tunnel, TrackKind::Tunnel]);
+        // 6 steps at grade, 2 portals and 3 steps inside the tunnel (including the exit).
+        assert_eq!(route.cost, (6 + 2 * 5 + 3 * 3) * COST_PER_CELL);
+    }
+
+    #[test]
+    fn bridges_over_a_valley() {
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| if x == 4 { 0 } else { 50 });
+
+        let (route, _) = route_with_structures(
+            (0, 0),
+            (9, 0),
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Eight,
+            STRUCTURES,
+        )
+        .unwrap();
+
+        assert_consistent(&route, &height_map);
+        assert!(route
+            .cells
+            .iter()
+            .any(|cell| cell.position.0 == 4 && cell.kind == TrackKind::Bridge));
+    }
+
+    #[test]
+    fn structures_respect_max_length() {
+        let height_map =
+            HeightMap::from_fn(10, 10, |(x, _)| if (2..8).contains(&x) { 50 } else { 0 });
+        let cost_model = MaxGrade {
+            inner: AbsoluteClimb::default(),
+            max_grade: 1.0,
+            steep_steps: SteepSteps::Forbid,
+        };
+
+        // The ridge is 6 cells thick, so a tunnel of at most 4 can't get through.
+        assert_eq!(
+            route_with_structures(
+                (0, 0),
+                (9, 0),
+                &height_map,
+                &cost_model,
+                Connectivity::Four,
+                STRUCTURES,
+            ),
+            Err(RoutingError::NoFeasibleRoute {
+                start: (0, 0),
+                end: (9, 0)
+            })
+        );
+
+        let mut structures = STRUCTURES;
+        structures.tunnel.as_mut().unwrap().max_length = 6;
+        let (route, _) = route_with_structures(
+            (0, 0),
+            (9, 0),
+            &height_map,
+            &cost_model,
+            Connectivity::Four,
+            structures,
+        )
+        .unwrap();
+        assert_consistent(&route, &height_map);
+    }
+
+    #[test]
+    fn structures_avoid_no_go_cells() {
+        let height_map = HeightMap::from_fn(10, 10, |(x, _)| if x == 4 || x == 5 { 50 } else { 0 });
+        // Only the far side of the ridge may be tunnelled through.
+        let mask = Raster::from_fn(10, 10, |(x, y)| x == 4 && y < 8);
+        let cost_model = NoGo {
+            inner: AbsoluteClimb::default(),
+            mask: mask.clone(),
+        };
+
+        let (route, _) = route_with_structures(
+            (0, 0),
+            (9, 0),
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            STRUCTURES,
+        )
+        .unwrap();
+
+        assert_consistent(&route, &height_map);
+        assert!(route.cells.iter().all(|cell| !mask[cell.position]));
+        assert!(route
+            .cells
+            .iter()
+            .any(|cell| cell.kind == TrackKind::Tunnel));
+    }
+}
@@ -0,0 +1,362 @@
+// Recording what a search explored, to see how a cost model steers it.
+
+use std::io::{self, Write};
+
+use crate::{
+    check_bounds,
+    cost_model::CostModel,
+    datatypes::{HeightMap, Raster},
+    expand_cell, grow_search_tree,
+    progress::{NoObserver, SearchObserver},
+    Connectivity, Route, RoutingError, SearchStats,
+};
+
+/// Which value of a `SearchTrace` to draw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceLayer {
+    Order,
+    Cost,
+}
+
+/// What a search found out about every cell it expanded. Both rasters are indexed like the
+/// height map, with `None` for the cells that were never expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchTrace {
+    /// When each cell was expanded, counting from 0 for the start. A cell that was reopened
+    /// (which only happens with an inconsistent heuristic) keeps its last expansion.
+    pub order: Raster<Option<usize>>,
+    /// The settled cost of each cell.
+    pub cost: Raster<Option<usize>>,
+}
+
+/// Like `a_star`, but also returns the trace of the search.
+pub fn traced_a_star(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    heuristic: impl Fn((usize, usize), (usize, usize), &HeightMap) -> usize,
+) -> Result<(Route, SearchTrace), RoutingError> {
+    traced_a_star_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        heuristic,
+        &mut NoObserver,
+    )
+}
+
+/// Like `traced_a_star`, but reports its progress to `observer`, which may cancel the search.
+pub fn traced_a_star_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    heuristic: impl Fn((usize, usize), (usize, usize), &HeightMap) -> usize,
+    observer: &mut impl SearchObserver,
+) -> Result<(Route, SearchTrace), RoutingError> {
+    check_bounds(height_map, &[start, end])?;
+
+    let tree = grow_search_tree(
+        start,
+        |position| position == end,
+        |position| heuristic(position, end, height_map),
+        |curr, relaxations| expand_cell(curr, relaxations, height_map, cost_model, connectivity),
+        connectivity.can_reopen(),
+        &mut SearchStats::default(),
+        observer,
+    )?;
+
+    let mut trace = SearchTrace {
+        order: height_map.raster(None),
+        cost: height_map.raster(None),
+    };
+    for (i, &position) in tree.expanded.iter().enumerate() {
+        trace.order[position] = Some(i);
+        trace.cost[position] = tree.cost(position);
+    }
+
+    let (Some(path), Some(cost)) = (tree.path(end), tree.cost(end)) else {
+        return Err(RoutingError::NoFeasibleRoute { start, end });
+    };
+    Ok((Route { path, cost }, trace))
+}
+
+/// Like `dijkstra`, but also returns the trace of the search.
+pub fn traced_dijkstra(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+) -> Result<(Route, SearchTrace), RoutingError> {
+    traced_dijkstra_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        &mut NoObserver,
+    )
+}
+
+/// Like `traced_dijkstra`, but reports its progress to `observer`, which may cancel the
+/// search.
+pub fn traced_dijkstra_observed(
+    start: (usize, usize),
+    end: (usize, usize),
+    height_map: &HeightMap,
+    cost_model: &impl CostModel,
+    connectivity: Connectivity,
+    observer: &mut impl SearchObserver,
+) -> Result<(Route, SearchTrace), RoutingError> {
+    traced_a_star_observed(
+        start,
+        end,
+        height_map,
+        cost_model,
+        connectivity,
+        |_, _, _| 0,
+        observer,
+    )
+}
+
+impl SearchTrace {
+    /// The values of `layer` scaled to between 0 and 1.
+    fn normalised(&self, layer: TraceLayer) -> Raster<Option<f64>> {
+        let values = match layer {
+            TraceLayer::Order => &self.order,
+            TraceLayer::Cost => &self.cost,
+        };
+        let max = values.values().iter().flatten().copied().max().unwrap_or(0);
+        values.map(|value| value.map(|value| value as f64 / max.max(1) as f64))
+    }
+
+    /// Writes `layer` as a binary greyscale PGM image, with x running to the right and y
+    /// running down. Unexplored cells are black, and explored cells go from dark to white
+    /// as their value grows.
+    pub fn write_pgm(&self, layer: TraceLayer, w: &mut impl Write) -> io::Result<()> {
+        let values = self.normalised(layer);
+        write!(w, "P5\n{} {}\n255\n", values.width(), values.height())?;
+        let pixels = values
+            .values()
+            .iter()
+            .map(|value| value.map_or(0, |value| 1 + (value * 254.0).round() as u8))
+            .collect::<Vec<_>>();
+        w.write_all(&pixels)
+    }
+
+    /// Writes `layer` as a binary false-colour PPM image, running from blue for the
+    /// smallest values through green to red for the largest. Unexplored cells are black,
+    /// and the cells of `highlight` (usually the route) are white.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput`, before writing anything, if a cell of
+    /// `highlight` lies outside of the trace.
+    pub fn write_ppm(
+        &self,
+        layer: TraceLayer,
+        highlight: &[(usize, usize)],
+        w: &mut impl Write,
+    ) -> io::Result<()> {
+        let values = self.normalised(layer);
+        let (x_len, y_len) = values.size();
+        let mut pixels = values.map(|value| value.map_or([0; 3], false_colour));
+        for &(x, y) in highlight {
+            if !pixels.contains((x, y)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "highlighted cell {:?} is outside of the {}x{} trace",
+                        (x, y),
+                        x_len,
+                        y_len
+                    ),
+                ));
+            }
+            pixels[(x, y)] = [255; 3];
+        }
+
+        write!(w, "P6\n{} {}\n255\n", x_len, y_len)?;
+        w.write_all(&pixels.values().concat())
+    }
+
+    /// The explored cells as voxels just above the terrain, split into `bands` groups of
+    /// equal value ranges of `layer`, from the smallest values up.
+    ///
+    /// Voxels can't go below 0, so cells with a negative height are drawn as if they were
+    /// at height 0.
+    ///
+    /// `magica_voxel::write_to_vox` gives every group its own colour, so the bands can go
+    /// straight after the other models.
+    pub fn vox_layers(
+        &self,
+        layer: TraceLayer,
+        height_map: &HeightMap,
+        bands: usize,
+    ) -> Vec<Vec<(usize, usize, usize)>> {
+        assert!(bands > 0, "there must be at least one band");
+        let mut groups = vec![Vec::new(); bands];
+        for ((x, y), value) in self.normalised(layer).iter() {
+            if let Some(value) = value {
+                let band = ((value * bands as f64) as usize).min(bands - 1);
+                groups[band].push((x, y, height_map[(x, y)].max(0) as usize + 1));
+            }
+        }
+        groups
+    }
+}
+
+/// A colour for `value` between 0 and 1, going blue, cyan, green, yellow, red.
+fn false_colour(value: f64) -> [u8; 3] {
+    let scaled = value.clamp(0.0, 1.0) * 4.0;
+    let ramp = |t: f64| (t.clamp(0.0, 1.0) * 255.0).round() as u8;
+    match scaled as usize {
+        0 => [0, ramp(scaled), 255],
+        1 => [0, 255, ramp(2.0 - scaled)],
+        2 => [ramp(scaled - 2.0), 255, 0],
+        _ => [255, ramp(4.0 - scaled), 0],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cost_model::AbsoluteClimb, dijkstra};
+
+    fn height_map() -> HeightMap {
+        HeightMap::from_fn(30, 20, |(x, y)| (x as i32 - 15).abs() / 3 + y as i32 % 4)
+    }
+
+    #[test]
+    fn records_order_and_cost() {
+        let height_map = height_map();
+        let cost_model = AbsoluteClimb::default();
+        let (start, end) = ((2, 3), (27, 16));
+
+        let (route, trace) =
+            traced_dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight).unwrap();
+        assert_eq!(
+            route,
+            dijkstra(start, end, &height_map, &cost_model, Connectivity::Eight).unwrap()
+        );
+        assert_eq!(trace.order[start], Some(0));
+        assert_eq!(trace.cost[start], Some(0));
+        assert_eq!(trace.cost[end], Some(route.cost));
+
+        // Every expanded cell has a distinct place in the order, and Dijkstra expands them
+        // in order of cost.
+        let mut expanded = trace
+            .order
+            .values()
+            .iter()
+            .zip(trace.cost.values())
+            .filter_map(|(&order, &cost)| Some((order?, cost?)))
+            .collect::<Vec<_>>();
+        expanded.sort();
+        assert!(expanded
+            .iter()
+            .enumerate()
+            .all(|(i, &(order, _))| i == order));
+        assert!(expanded.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(trace.order[end], Some(expanded.len() - 1));
+
+        // A* with a good lower bound explores less.
+        let (_, a_star_trace) = traced_a_star(
+            start,
+            end,
+            &height_map,
+            &cost_model,
+            Connectivity::Eight,
+            |from, to, height_map| {
+                cost_model.lower_bound(height_map, from, to, Connectivity::Eight)
+            },
+        )
+        .unwrap();
+        let count = |trace: &SearchTrace| trace.order.values().iter().flatten().count();
+        assert!(count(&a_star_trace) < count(&trace));
+    }
+
+    #[test]
+    fn exports_images_and_voxels() {
+        let height_map = height_map();
+        let (route, trace) = traced_dijkstra(
+            (2, 3),
+            (27, 16),
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Eight,
+        )
+        .unwrap();
+        let explored = trace.order.values().iter().flatten().count();
+        let header = b"P5\n30 20\n255\n";
+
+        let mut pgm = Vec::new();
+        trace.write_pgm(TraceLayer::Order, &mut pgm).unwrap();
+        assert_eq!(&pgm[..header.len()], header);
+        let pixels = &pgm[header.len()..];
+        assert_eq!(pixels.len(), 30 * 20);
+        assert_eq!(pixels.iter().filter(|&&pixel| pixel > 0).count(), explored);
+        // The start was expanded first, the end last.
+        assert_eq!(pixels[3 * 30 + 2], 1);
+        assert_eq!(pixels[16 * 30 + 27], 255);
+
+        let mut ppm = Vec::new();
+        trace
+            .write_ppm(TraceLayer::Cost, &route.path, &mut ppm)
+            .unwrap();
+        let header = b"P6\n30 20\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        let pixels = ppm[header.len()..].chunks(3).collect::<Vec<_>>();
+        assert_eq!(pixels.len(), 30 * 20);
+        assert!(route
+            .path
+            .iter()
+            .all(|&(x, y)| pixels[y * 30 + x] == [255, 255, 255]));
+
+        let layers = trace.vox_layers(TraceLayer::Cost, &height_map, 3);
+        assert_eq!(layers.len(), 3);
+        assert_eq!(layers.iter().map(Vec::len).sum::<usize>(), explored);
+        assert!(layers[0].contains(&(2, 3, height_map[(2, 3)] as usize + 1)));
+        assert!(layers[2].contains(&(27, 16, height_map[(27, 16)] as usize + 1)));
+    }
+
+    #[test]
+    fn rejects_highlights_outside_the_trace() {
+        let (_, trace) = traced_dijkstra(
+            (2, 3),
+            (27, 16),
+            &height_map(),
+            &AbsoluteClimb::default(),
+            Connectivity::Eight,
+        )
+        .unwrap();
+
+        let mut ppm = Vec::new();
+        let error = trace
+            .write_ppm(TraceLayer::Order, &[(2, 3), (30, 0)], &mut ppm)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(ppm.is_empty());
+    }
+
+    #[test]
+    fn negative_heights_sit_on_the_ground() {
+        let height_map = HeightMap::from_fn(8, 8, |(x, _)| x as i32 - 4);
+        let (_, trace) = traced_dijkstra(
+            (0, 0),
+            (7, 7),
+            &height_map,
+            &AbsoluteClimb::default(),
+            Connectivity::Eight,
+        )
+        .unwrap();
+
+        let layers = trace.vox_layers(TraceLayer::Order, &height_map, 1);
+        assert!(layers[0].contains(&(0, 0, 1)));
+        assert!(layers[0].contains(&(7, 7, 4)));
+    }
+}
@@ -1,199 +1,148 @@
-use std::ops::Deref;
-
-// TODO: create OctreeD0 through OctreeD31 (hardcoded types)
-type OctreeD0 = OctreeNode<PointData>;
-type OctreeD1 = OctreeNode<Option<Box<OctreeD0>>>;
-type OctreeD2 = OctreeNode<Option<Box<OctreeD1>>>;
-type OctreeD3 = OctreeNode<Option<Box<OctreeD2>>>;
-type OctreeD4 = OctreeNode<Option<Box<OctreeD3>>>;
-type OctreeD5 = OctreeNode<Option<Box<OctreeD4>>>;
-type OctreeD6 = OctreeNode<Option<Box<OctreeD5>>>;
-type OctreeD7 = OctreeNode<Option<Box<OctreeD6>>>;
-type OctreeD8 = OctreeNode<Option<Box<OctreeD7>>>;
-type OctreeD9 = OctreeNode<Option<Box<OctreeD8>>>;
-type OctreeD10 = OctreeNode<Option<Box<OctreeD9>>>;
-type OctreeD11 = OctreeNode<Option<Box<OctreeD10>>>;
-type OctreeD12 = OctreeNode<Option<Box<OctreeD11>>>;
-type OctreeD13 = OctreeNode<Option<Box<OctreeD12>>>;
-type OctreeD14 = OctreeNode<Option<Box<OctreeD13>>>;
-type OctreeD15 = OctreeNode<Option<Box<OctreeD14>>>;
-type OctreeD16 = OctreeNode<Option<Box<OctreeD15>>>;
-type OctreeD17 = OctreeNode<Option<Box<OctreeD16>>>;
-type OctreeD18 = OctreeNode<Option<Box<OctreeD17>>>;
-type OctreeD19 = OctreeNode<Option<Box<OctreeD18>>>;
-type OctreeD20 = OctreeNode<Option<Box<OctreeD19>>>;
-type OctreeD21 = OctreeNode<Option<Box<OctreeD20>>>;
-type OctreeD22 = OctreeNode<Option<Box<OctreeD21>>>;
-type OctreeD23 = OctreeNode<Option<Box<OctreeD22>>>;
-type OctreeD24 = OctreeNode<Option<Box<OctreeD23>>>;
-type OctreeD25 = OctreeNode<Option<Box<OctreeD24>>>;
-type OctreeD26 = OctreeNode<Option<Box<OctreeD25>>>;
-type OctreeD27 = OctreeNode<Option<Box<OctreeD26>>>;
-type OctreeD28 = OctreeNode<Option<Box<OctreeD27>>>;
-type OctreeD29 = OctreeNode<Option<Box<OctreeD28>>>;
-type OctreeD30 = OctreeNode<Option<Box<OctreeD29>>>;
-type OctreeD31 = OctreeNode<Option<Box<OctreeD30>>>;
-
-enum Octree {
-    OctreeD0(OctreeD0),
-    OctreeD1(OctreeD1),
-    OctreeD2(OctreeD2),
-    OctreeD3(OctreeD3),
-    OctreeD4(OctreeD4),
-    OctreeD5(OctreeD5),
-    OctreeD6(OctreeD6),
-    OctreeD7(OctreeD7),
-    OctreeD8(OctreeD8),
-    OctreeD9(OctreeD9),
-    OctreeD10(OctreeD10),
-    OctreeD11(OctreeD11),
-    OctreeD12(OctreeD12),
-    OctreeD13(OctreeD13),
-    OctreeD14(OctreeD14),
-    OctreeD15(OctreeD15),
-    OctreeD16(OctreeD16),
-    OctreeD17(OctreeD17),
-    OctreeD18(OctreeD18),
-    OctreeD19(OctreeD19),
-    OctreeD20(OctreeD20),
-    OctreeD21(OctreeD21),
-    OctreeD22(OctreeD22),
-    OctreeD23(OctreeD23),
-    OctreeD24(OctreeD24),
-    OctreeD25(OctreeD25),
-    OctreeD26(OctreeD26),
-    OctreeD27(OctreeD27),
-    OctreeD28(OctreeD28),
-    OctreeD29(OctreeD29),
-    OctreeD30(OctreeD30),
-    OctreeD31(OctreeD31),
+// A sparse voxel octree, for keeping track of which voxels of a large block are taken.
+
+/// A sparse voxel octree of occupied voxels.
+///
+/// The root covers a cube of `2^depth` voxels per side from the origin, and only the
+/// octants that contain occupied voxels are stored, so empty space costs nothing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseVoxelOctree {
+    depth: u32,
+    /// The 8 children of every node, as indices into `nodes`, or 0 for an empty octant
+    /// (the root is node 0, so nothing points at it). The children of a node one level
+    /// above the voxels are 1 for an occupied voxel.
+    nodes: Vec<[usize; 8]>,
 }
-type Depth = u32;
-
-struct MaskUInt<const B: Depth>(Depth);
-
-impl<const B: Depth> TryFrom<Depth> for MaskUInt<B> {
-    type Error = String;
 
-    fn try_from(value: Depth) -> Result<Self, Self::Error> {
-        if value < 1 << B {
-            Ok(Self(value))
-        } else {
-            Err("Too large".into())
+impl SparseVoxelOctree {
+    /// An empty octree that can hold every voxel of a `size` block.
+    pub fn new(size: (usize, usize, usize)) -> Self {
+        let side = size.0.max(size.1).max(size.2).max(2);
+        Self {
+            depth: side.next_power_of_two().trailing_zeros(),
+            nodes: vec![[0; 8]],
         }
     }
-}
-
-impl<const B: Depth> MaskUInt<B> {
-    const fn remove_top_bit(self) -> MaskUInt<{ B - 1 }> {
-        // NOTE: We design the constructors such that self.0 is guaranteed to be less than 2 ^ Depth.
-        assert!(self.0 < (1 << B));
-        MaskUInt::<{ B - 1 }>(self.0 & !(1 << B))
-    }
-    const fn get_top_bit(&self) -> Depth {
-        // This invariant should hold given the design of the struct.
-        assert!(self.0 >> B <= 1);
-        self.0 >> B
-    }
-}
-
-type PointData = u32;
-type Coord<const B: Depth> = MaskUInt<B>;
-
-struct OctreePoint<const B: Depth> {
-    x: Coord<B>,
-    y: Coord<B>,
-    z: Coord<B>,
-}
 
-impl<const B: Depth> OctreePoint<B> {
-    fn decrease_depth(self) -> OctreePoint<{ B - 1 }> {
-        OctreePoint::<{ B - 1 }> {
-            x: self.x.remove_top_bit(),
-            y: self.y.remove_top_bit(),
-            z: self.z.remove_top_bit(),
+    /// Marks `voxel` as occupied.
+    ///
+    /// Panics if `voxel` lies outside of the block the octree was made for.
+    pub fn insert(&mut self, voxel: (usize, usize, usize)) {
+        let side = 1 << self.depth;
+        assert!(
+            voxel.0 < side && voxel.1 < side && voxel.2 < side,
+            "{:?} is outside of the octree",
+            voxel
+        );
+        let mut node = 0;
+        for level in (1..self.depth).rev() {
+            let octant = octant(voxel, level);
+            if self.nodes[node][octant] == 0 {
+                self.nodes[node][octant] = self.nodes.len();
+                self.nodes.push([0; 8]);
+            }
+            node = self.nodes[node][octant];
         }
+        self.nodes[node][octant(voxel, 0)] = 1;
     }
-}
-
-impl<const B: Depth> Deref for MaskUInt<B> {
-    type Target = Depth;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-/// For Octrees of depth 1, N will be an array of elements that correspond to each point (e.g., colors)
-/// For Octrees of depth > 1, N will be simply another OctreeNode.
-struct OctreeNode<E>([E; 8]);
-trait Nested<const D: Depth> {
-    fn contains_inner(&self, point: OctreePoint<D>) -> bool;
 
-    fn contains(&self, point: impl TryInto<OctreePoint<D>>) -> bool {
-        let Ok(coord) = point.try_into() else {
-            return false;
-        };
-        self.contains_inner(coord)
-    }
-    fn get_idx(point: &OctreePoint<D>) -> Depth {
-        (point.x.get_top_bit() << 2) | (point.y.get_top_bit() << 1) | (point.z.get_top_bit() << 0)
+    pub fn contains(&self, voxel: (usize, usize, usize)) -> bool {
+        self.any_in_box(voxel, voxel)
     }
-}
-impl Nested<0> for OctreeNode<PointData> {
-    fn contains_inner(&self, point: OctreePoint<0>) -> bool {
-        let idx = Self::get_idx(&point);
-        todo!("Need to determine what counts as an empty leaf")
-        // children.0[idx as usize] != 0
-    }
-}
 
-impl<T: Nested<{ D - 1 }>, const D: Depth> Nested<D> for OctreeNode<Option<Box<T>>> {
-    fn contains_inner(&self, point: OctreePoint<D>) -> bool {
-        let idx = Self::get_idx(&point);
-        if let Some(ref child) = self.0[idx as usize] {
-            return child.contains_inner(point.decrease_depth());
-        }
-        false
+    /// Whether any voxel in the box from `min` to `max` inclusive is occupied.
+    pub fn any_in_box(&self, min: (usize, usize, usize), max: (usize, usize, usize)) -> bool {
+        self.any_in_node(
+            0,
+            [0; 3],
+            self.depth,
+            [min.0, min.1, min.2],
+            [max.0, max.1, max.2],
+        )
     }
-}
 
-impl<E: Default + Copy> OctreeNode<E> {
-    fn new() -> Self {
-        Self([E::default(); 8])
+    /// Whether any voxel of the box from `min` to `max` inclusive is occupied, within the
+    /// cube of side `2^level` at `origin` that `node` covers.
+    fn any_in_node(
+        &self,
+        node: usize,
+        origin: [usize; 3],
+        level: u32,
+        min: [usize; 3],
+        max: [usize; 3],
+    ) -> bool {
+        let half = 1 << (level - 1);
+        self.nodes[node].iter().enumerate().any(|(octant, &child)| {
+            let corner = [
+                origin[0] + (octant >> 2 & 1) * half,
+                origin[1] + (octant >> 1 & 1) * half,
+                origin[2] + (octant & 1) * half,
+            ];
+            let overlaps =
+                (0..3).all(|axis| corner[axis] <= max[axis] && min[axis] < corner[axis] + half);
+            child != 0
+                && overlaps
+                && (level == 1 || self.any_in_node(child, corner, level - 1, min, max))
+        })
     }
 }
 
-struct OctreeCoord<const B: Depth> {
-    x: Coord<B>,
-    y: Coord<B>,
-    z: Coord<B>,
+/// Which of the 8 children of a node at `level` above the voxels `voxel` falls in.
+fn octant(voxel: (usize, usize, usize), level: u32) -> usize {
+    (voxel.0 >> level & 1) << 2 | (voxel.1 >> level & 1) << 1 | (voxel.2 >> level & 1)
 }
-impl<const D: Depth> TryFrom<u32> for OctreeCoord<D> {
-    type Error = &'static str;
 
-    fn try_from(value: u32) -> Result<Self, Self::Error> {
-        todo!()
+impl Extend<(usize, usize, usize)> for SparseVoxelOctree {
+    fn extend<I: IntoIterator<Item = (usize, usize, usize)>>(&mut self, voxels: I) {
+        for voxel in voxels {
+            self.insert(voxel);
+        }
     }
 }
 
-impl SparseVoxelOctree {
-    /// Abs designed for SVO boundary tests, where the SVO
-    /// is biased such that it can contain between [-k, k)
-    /// where k is the length of an octant. This shifts
-    /// [-k, -1] rightwards before negating so that the resulting value
-    /// is between [0, k).
-    const fn abs_svo(n: i32) -> u32 {
-        (if n < 0 { -(n + 1) } else { n }) as u32
-    }
-    const fn is_in_bounds(&self, v: &Voxel) -> bool {
-        // Get the max of the coordinates via bitwise-or, then check if it is less than the length of an octant.
-        // Note the use of abs_svo due to the negative skewed coordinate system.
-        (Self::abs_svo(v.x) | Self::abs_svo(v.y) | Self::abs_svo(v.z)) < self.octant_length
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn matches_hash_set() {
+        let size = (13, 7, 20);
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move |len: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % len as u64) as usize
+        };
+        let voxels = (0..40)
+            .map(|_| (next(size.0), next(size.1), next(size.2)))
+            .collect::<HashSet<_>>();
+        let mut octree = SparseVoxelOctree::new(size);
+        octree.extend(voxels.iter().copied());
+
+        for _ in 0..500 {
+            let (a, b) = (
+                (next(size.0), next(size.1), next(size.2)),
+                (next(size.0), next(size.1), next(size.2)),
+            );
+            assert_eq!(octree.contains(a), voxels.contains(&a), "{:?}", a);
+            let (min, max) = (
+                (a.0.min(b.0), a.1.min(b.1), a.2.min(b.2)),
+                (a.0.max(b.0), a.1.max(b.1), a.2.max(b.2)),
+            );
+            let expected = voxels.iter().any(|&(x, y, z)| {
+                (min.0..=max.0).contains(&x)
+                    && (min.1..=max.1).contains(&y)
+                    && (min.2..=max.2).contains(&z)
+            });
+            assert_eq!(
+                octree.any_in_box(min, max),
+                expected,
+                "{:?} to {:?}",
+                min,
+                max
+            );
+        }
     }
 }
-
-struct Voxel {
-    x: i32,
-    y: i32,
-    z: i32,
-}
@@ -0,0 +1,419 @@
+// Routing through 3D space for deep lines, over voxels instead of the terrain surface.
+
+use std::collections::HashSet;
+
+use crate::{
+    best_first_search,
+    cost_model::{to_cost, COST_PER_CELL},
+    progress::{NoObserver, SearchObserver},
+    voxel_model::SparseVoxelOctree,
+    Connectivity, Relaxation, RoutingError, SearchStats,
+};
+
+/// Which voxels are already taken, by existing tunnels or anything else the route must
+/// keep clear of.
+///
+/// `SparseVoxelOctree` stores this sparsely and answers the clearance checks of `voxel_a_star`
+/// a whole box at a time. A `HashSet` of voxels also does the job for small volumes.
+pub trait VoxelOccupancy {
+    fn is_occupied(&self, voxel: (usize, usize, usize)) -> bool;
+
+    /// Whether any voxel in the box from `min` to `max` inclusive is occupied.
+    fn is_any_occupied(&self, min: (usize, usize, usize), max: (usize, usize, usize)) -> bool {
+        (min.0..=max.0)
+            .any(|x| (min.1..=max.1).any(|y| (min.2..=max.2).any(|z| self.is_occupied((x, y, z)))))
+    }
+}
+
+impl VoxelOccupancy for HashSet<(usize, usize, usize)> {
+    fn is_occupied(&self, voxel: (usize, usize, usize)) -> bool {
+        self.contains(&voxel)
+    }
+}
+
+impl VoxelOccupancy for SparseVoxelOctree {
+    fn is_occupied(&self, voxel: (usize, usize, usize)) -> bool {
+        self.contains(voxel)
+    }
+
+    fn is_any_occupied(&self, min: (usize, usize, usize), max: (usize, usize, usize)) -> bool {
+        self.any_in_box(min, max)
+    }
+}
+
+/// What a voxel route costs, and how it may move.
+#[derive(Debug, Clone, Copy)]
+pub struct VoxelLimits {
+    /// The largest change in z per voxel of horizontal distance, averaged over the run
+    /// since the previous change. Routes never move straight up or down, and stay level
+    /// if this is 0.
+    pub max_grade: f64,
+    /// How many voxels must separate the route from any occupied voxel, in every direction.
+    pub clearance: usize,
+    /// Charged per voxel of z change, in units of `COST_PER_CELL`, on top of the
+    /// horizontal distance.
+    pub climb_cost: f64,
+}
+
+/// A route through voxels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxelRoute {
+    /// Every voxel on the path, from the start to the end inclusive.
+    pub path: Vec<(usize, usize, usize)>,
+    pub cost: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct VoxelState {
+    position: (i32, i32, i32),
+    /// Horizontal distance since z last changed, in units of `COST_PER_CELL`.
+    /// This is capped at the climb spacing, so that all states which may climb are merged.
+    since_climb: usize,
+}
+
+/// A* search through the voxels of a `size` block from `start` to `end`, which keeps
+/// `limits.clearance` away from every voxel of `occupancy`.
+///
+/// Every step moves to a horizontal neighbour (as given by `connectivity`, with
+/// `Connectivity::AnyAngle` treated like `Connectivity::Eight`) and may also go up or down
+/// by one voxel, as long as the route has run far enough since the last change in z to stay
+/// within `limits.max_grade`.
+///
+/// Returns `RoutingError::InvalidSetting` if `limits.max_grade` is negative or NaN.
+pub fn voxel_a_star(
+    start: (usize, usize, usize),
+    end: (usize, usize, usize),
+    size: (usize, usize, usize),
+    occupancy: &impl VoxelOccupancy,
+    connectivity: Connectivity,
+    limits: VoxelLimits,
+) -> Result<VoxelRoute, RoutingError> {
+    voxel_a_star_observed(
+        start,
+        end,
+        size,
+        occupancy,
+        connectivity,
+        limits,
+        &mut NoObserver,
+    )
+}
+
+/// Like `voxel_a_star`, but reports its progress to `observer`, which may cancel the search.
+pub fn voxel_a_star_observed(
+    start: (usize, usize, usize),
+    end: (usize, usize, usize),
+    size: (usize, usize, usize),
+    occupancy: &impl VoxelOccupancy,
+    connectivity: Connectivity,
+    limits: VoxelLimits,
+    observer: &mut impl SearchObserver,
+) -> Result<VoxelRoute, RoutingError> {
+    if size.0 == 0 || size.1 == 0 || size.2 == 0 {
+        return Err(RoutingError::EmptyHeightMap);
+    }
+    if let Some(&voxel) = [start, end]
+        .iter()
+        .find(|voxel| voxel.0 >= size.0 || voxel.1 >= size.1 || voxel.2 >= size.2)
+    {
+        return Err(RoutingError::VoxelOutOfBounds { voxel, size });
+    }
+    if limits.max_grade.is_nan() || limits.max_grade < 0.0 {
+        return Err(RoutingError::InvalidSetting {
+            name: "max grade",
+            reason: "must be a non-negative number",
+        });
+    }
+
+    let as_usize = |(x, y, z): (i32, i32, i32)| (x as usize, y as usize, z as usize);
+    let in_bounds = |(x, y, z): (i32, i32, i32)| {
+        x >= 0
+            && y >= 0
+            && z >= 0
+            && (x as usize) < size.0
+            && (y as usize) < size.1
+            && (z as usize) < size.2
+    };
+    let clearance = limits.clearance;
+    let is_clear = |position: (i32, i32, i32)| {
+        let (x, y, z) = as_usize(position);
+        !occupancy.is_any_occupied(
+            (
+                x.saturating_sub(clearance),
+                y.saturating_sub(clearance),
+                z.saturating_sub(clearance),
+            ),
+            (
+                (x + clearance).min(size.0 - 1),
+                (y + clearance).min(size.1 - 1),
+                (z + clearance).min(size.2 - 1),
+            ),
+        )
+    };
+    let offsets = match connectivity {
+        Connectivity::AnyAngle => Connectivity::Eight,
+        connectivity => connectivity,
+    }
+    .neighbor_offsets();
+    // How far the route must run between changes in z, in units of `COST_PER_CELL`,
+    // or `None` if it must stay level.
+    let spacing = (limits.max_grade > 0.0).then(|| to_cost(1.0 / limits.max_grade));
+
+    let heuristic = |(x, y, z): (i32, i32, i32)| {
+        let horizontal = ((x - end.0 as i32) as f64).hypot((y - end.1 as i32) as f64);
+        let climb = (z - end.2 as i32).abs() as f64;
+        ((horizontal + limits.climb_cost * climb) * COST_PER_CELL as f64).floor() as usize
+    };
+
+    let start_position = (start.0 as i32, start.1 as i32, start.2 as i32);
+    if !is_clear(start_position) || !is_clear((end.0 as i32, end.1 as i32, end.2 as i32)) {
+        return Err(RoutingError::NoFeasibleVoxelRoute { start, end });
+    }
+
+    let result = best_first_search(
+        VoxelState {
+            position: start_position,
+            since_climb: spacing.unwrap_or(0),
+        },
+        |state| as_usize(state.position) == end,
+        |state| heuristic(state.position),
+        |curr, relaxations| {
+            let curr = curr.position;
+            let (x, y, z) = curr.position;
+            for &(dx, dy) in offsets {
+                let length = to_cost(((dx * dx + dy * dy) as f64).sqrt());
+                for dz in -1..=1 {
+                    let next = (x + dx, y + dy, z + dz);
+                    if !in_bounds(next) || !is_clear(next) {
+                        continue;
+                    }
+                    let run = curr.since_climb.saturating_add(length);
+                    let since_climb = match spacing {
+                        None if dz == 0 => 0,
+                        Some(spacing) if dz == 0 => run.min(spacing),
+                        Some(spacing) if run >= spacing => 0,
+                        _ => continue,
+                    };
+                    relaxations.push(Relaxation {
+                        position: VoxelState {
+                            position: next,
+                            since_climb,
+                        },
+                        from: curr,
+                        step_cost: length + to_cost(limits.climb_cost * dz.abs() as f64),
+                    });
+                }
+            }
+        },
+        true,
+        &mut SearchStats::default(),
+        observer,
+    )?;
+    let Some((path, cost)) = result else {
+        return Err(RoutingError::NoFeasibleVoxelRoute { start, end });
+    };
+
+    Ok(VoxelRoute {
+        path: path
+            .into_iter()
+            .map(|state| as_usize(state.position))
+            .collect(),
+        cost,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: VoxelLimits = VoxelLimits {
+        max_grade: 0.5,
+        clearance: 1,
+        climb_cost: 1.0,
+    };
+
+    fn assert_within_grade(path: &[(usize, usize, usize)], max_grade: f64) {
+        let mut since_climb = f64::INFINITY;
+        for step in path.windows(2) {
+            let (from, to) = (step[0], step[1]);
+            assert_ne!((from.0, from.1), (to.0, to.1), "moved straight up or down");
+            let length = (from.0.abs_diff(to.0) as f64).hypot(from.1.abs_diff(to.1) as f64);
+            since_climb += length;
+            if from.2 != to.2 {
+                assert!(
+                    since_climb * max_grade >= 1.0 - 1e-9,
+                    "{:?} is too steep",
+                    step
+                );
+                since_climb = 0.0;
+            }
+        }
+    }
+
+    #[test]
+    fn climbs_within_the_grade_limit() {
+        let route = voxel_a_star(
+            (0, 0, 0),
+            (19, 0, 5),
+            (20, 5, 10),
+            &HashSet::new(),
+            Connectivity::Eight,
+            LIMITS,
+        )
+        .unwrap();
+
+        assert_eq!(route.path.first(), Some(&(0, 0, 0)));
+        assert_eq!(route.path.last(), Some(&(19, 0, 5)));
+        assert_within_grade(&route.path, LIMITS.max_grade);
+        assert_eq!(route.cost, (19 + 5) * COST_PER_CELL);
+
+        // 5 climbs at least 2 cells apart need at least 9 cells, so the route has to
+        // double back to get there in 8.
+        let route = voxel_a_star(
+            (0, 0, 0),
+            (8, 0, 5),
+            (9, 1, 10),
+            &HashSet::new(),
+            Connectivity::Eight,
+            LIMITS,
+        )
+        .unwrap();
+        assert!(route.path.len() > 9);
+        assert_within_grade(&route.path, LIMITS.max_grade);
+    }
+
+    #[test]
+    fn keeps_clear_of_existing_tunnels() {
+        // An existing tunnel runs right across the block at z = 5.
+        let size = (20, 20, 10);
+        let existing = (0..20).map(|y| (10, y, 5)).collect::<HashSet<_>>();
+
+        let route = voxel_a_star(
+            (0, 10, 5),
+            (19, 10, 5),
+            size,
+            &existing,
+            Connectivity::Eight,
+            LIMITS,
+        )
+        .unwrap();
+
+        assert_within_grade(&route.path, LIMITS.max_grade);
+        for &(x, y, z) in &route.path {
+            for &(ex, ey, ez) in &existing {
+                let distance = x.abs_diff(ex).max(y.abs_diff(ey)).max(z.abs_diff(ez));
+                assert!(distance > LIMITS.clearance, "{:?} is too close", (x, y, z));
+            }
+        }
+        // The new line has to pass over or under it.
+        let crossing = route.path.iter().find(|voxel| voxel.0 == 10).unwrap();
+        assert!(crossing.2 >= 7 || crossing.2 <= 3);
+        assert!(route.cost > 19 * COST_PER_CELL);
+
+        // Ending right next to the existing tunnel breaks the clearance.
+        assert_eq!(
+            voxel_a_star(
+                (0, 10, 5),
+                (11, 10, 5),
+                size,
+                &existing,
+                Connectivity::Eight,
+                LIMITS
+            ),
+            Err(RoutingError::NoFeasibleVoxelRoute {
+                start: (0, 10, 5),
+                end: (11, 10, 5)
+            })
+        );
+
+        assert_eq!(
+            voxel_a_star(
+                (0, 0, 0),
+                (0, 0, 10),
+                size,
+                &existing,
+                Connectivity::Eight,
+                LIMITS
+            ),
+            Err(RoutingError::VoxelOutOfBounds {
+                voxel: (0, 0, 10),
+                size
+            })
+        );
+    }
+
+    #[test]
+    fn octree_routes_like_hash_set() {
+        let existing = (0..20).map(|y| (10, y, 5));
+        let mut octree = SparseVoxelOctree::new((20, 20, 10));
+        octree.extend(existing.clone());
+        assert_eq!(
+            voxel_a_star(
+                (0, 10, 5),
+                (19, 10, 5),
+                (20, 20, 10),
+                &octree,
+                Connectivity::Eight,
+                LIMITS
+            ),
+            voxel_a_star(
+                (0, 10, 5),
+                (19, 10, 5),
+                (20, 20, 10),
+                &existing.collect::<HashSet<_>>(),
+                Connectivity::Eight,
+                LIMITS
+            )
+        );
+    }
+
+    #[test]
+    fn zero_grade_stays_level() {
+        let flat = VoxelLimits {
+            max_grade: 0.0,
+            ..LIMITS
+        };
+        let route = voxel_a_star(
+            (0, 0, 3),
+            (9, 4, 3),
+            (10, 5, 10),
+            &HashSet::new(),
+            Connectivity::Eight,
+            flat,
+        )
+        .unwrap();
+        assert!(route.path.iter().all(|voxel| voxel.2 == 3));
+
+        assert_eq!(
+            voxel_a_star(
+                (0, 0, 3),
+                (9, 4, 4),
+                (10, 5, 10),
+                &HashSet::new(),
+                Connectivity::Eight,
+                flat
+            ),
+            Err(RoutingError::NoFeasibleVoxelRoute {
+                start: (0, 0, 3),
+                end: (9, 4, 4)
+            })
+        );
+
+        for max_grade in [-0.5, f64::NAN] {
+            assert!(matches!(
+                voxel_a_star(
+                    (0, 0, 3),
+                    (9, 4, 3),
+                    (10, 5, 10),
+                    &HashSet::new(),
+                    Connectivity::Eight,
+                    VoxelLimits {
+                        max_grade,
+                        ..LIMITS
+                    }
+                ),
+                Err(RoutingError::InvalidSetting { .. })
+            ));
+        }
+    }
+}